@@ -0,0 +1,81 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use image::{codecs::jpeg::JpegEncoder, ColorType, Rgb, RgbImage};
+use mh_map::{jpeg, new_map, render};
+
+/// 生成 rows x cols 个单元的地图，每个单元都是完整的 320x240 JPEG (2GPJ)，没有遮罩
+fn synthetic_map(rows: u32, cols: u32) -> Vec<u8> {
+    let tile = RgbImage::from_pixel(320, 240, Rgb([90, 120, 60]));
+    let mut jpeg = vec![];
+    let mut encoder = JpegEncoder::new_with_quality(&mut jpeg, 80);
+    encoder.encode(&tile, 320, 240, ColorType::Rgb8).unwrap();
+
+    let count = rows * cols;
+    let mut bytes = b"0.1M".to_vec();
+    bytes.extend_from_slice(&(cols * 320).to_le_bytes());
+    bytes.extend_from_slice(&(rows * 240).to_le_bytes());
+
+    // 文件头 + 索引表 + 空的遮罩表之后就是单元数据
+    let units_start = 12 + count * 4 + 8;
+    let unit_len = (4 + 8 + jpeg.len()) as u32;
+    for i in 0..count {
+        bytes.extend_from_slice(&(units_start + i * unit_len).to_le_bytes());
+    }
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+
+    for _ in 0..count {
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(b"2GPJ");
+        bytes.extend_from_slice(&(jpeg.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&jpeg);
+    }
+    bytes
+}
+
+fn samples() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("1003", include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/1003.map")).to_vec()),
+        ("small", synthetic_map(2, 2)),
+        ("large", synthetic_map(16, 16)),
+    ]
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_from_bytes");
+    for (name, bytes) in samples() {
+        group.bench_function(name, |b| {
+            b.iter(|| new_map::decode_from_bytes(&bytes).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render");
+    group.sample_size(10);
+    for (name, bytes) in samples() {
+        let map = new_map::decode_from_bytes(&bytes).unwrap();
+        group.bench_function(name, |b| b.iter(|| render::render(&map).unwrap()));
+        #[cfg(feature = "rayon")]
+        group.bench_function(format!("{}_parallel", name), |b| {
+            b.iter(|| render::render_parallel(&map).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_fix_jpeg(c: &mut Criterion) {
+    // 大图里的 FF 字节足够多，能看出逐字节插入和线性拷贝的差别
+    let tile = RgbImage::from_fn(2048, 2048, |x, y| {
+        Rgb([(x * 7) as u8, (y * 13) as u8, (x ^ y) as u8])
+    });
+    let mut input = vec![];
+    let mut encoder = JpegEncoder::new_with_quality(&mut input, 95);
+    encoder.encode(&tile, 2048, 2048, ColorType::Rgb8).unwrap();
+
+    let mut out = vec![];
+    c.bench_function("fix_jpeg", |b| b.iter(|| jpeg::fix_jpeg(&input, &mut out)));
+}
+
+criterion_group!(benches, bench_decode, bench_render, bench_fix_jpeg);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+use std::{env, path::Path, process};
+
+use mh_map::{new_map, render};
+
+/// 把地图拼接成一张图片
+///
+/// 用法: cargo run --example render -- <地图文件> [输出图片]
+/// 不指定输出图片时保存为同目录下的同名 png
+fn main() -> anyhow::Result<()> {
+    let mut args = env::args().skip(1);
+    let input = match args.next() {
+        Some(input) => input,
+        None => {
+            eprintln!("usage: render <map file> [output image]");
+            process::exit(1);
+        }
+    };
+    let output = args
+        .next()
+        .unwrap_or_else(|| Path::new(&input).with_extension("png").to_string_lossy().into_owned());
+
+    let map = new_map::decode(&input)?;
+    let image = render::render(&map)?;
+    image.save(&output)?;
+
+    println!("{} -> {} ({}x{})", input, output, image.width(), image.height());
+    Ok(())
+}
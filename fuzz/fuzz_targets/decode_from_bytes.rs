@@ -0,0 +1,11 @@
+//! `cargo fuzz run decode_from_bytes`
+//!
+//! 任意字节都只能返回 Ok 或 Err，不能 panic、abort 或者分配超大的内存
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mh_map::new_map::decode_from_bytes;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_from_bytes(data);
+});
@@ -0,0 +1,79 @@
+//! 批量解码大量地图文件
+
+use std::{
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use crate::new_map::{decode, Map};
+
+/// 用 concurrency 个线程并行解码 paths 中的文件，按完成的顺序返回结果
+///
+/// 线程从同一个队列里取文件，解码快的线程会多取一些。已经解码完、还没被取走的结果
+/// 最多 buffer 个，满了之后线程会等待，所以同时在内存中的地图最多 buffer + concurrency 个。
+/// 提前丢弃返回的迭代器时，线程解码完手上的文件就会退出
+pub fn decode_many<I, P>(
+    paths: I,
+    concurrency: usize,
+    buffer: usize,
+) -> impl Iterator<Item = (PathBuf, anyhow::Result<Map>)>
+where
+    I: IntoIterator<Item = P>,
+    P: Into<PathBuf>,
+{
+    let queue = paths.into_iter().map(Into::into).collect::<Vec<PathBuf>>();
+    let queue = Arc::new(Mutex::new(queue.into_iter()));
+    let (sender, receiver) = mpsc::sync_channel(buffer);
+
+    for _ in 0..concurrency.max(1) {
+        let queue = Arc::clone(&queue);
+        let sender = sender.clone();
+        thread::spawn(move || loop {
+            // 取到文件后马上释放锁，解码时不占着队列
+            let path = match queue.lock() {
+                Ok(mut queue) => queue.next(),
+                Err(_) => None,
+            };
+            let path = match path {
+                Some(path) => path,
+                None => break,
+            };
+            let map = decode(&path);
+            if sender.send((path, map)).is_err() {
+                break;
+            }
+        });
+    }
+    receiver.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+
+    #[test]
+    fn decode_many_returns_every_file() {
+        let dir = std::env::temp_dir();
+        let mut paths = (0..3)
+            .map(|i| dir.join(format!("mh_map_decode_many_{}.map", i)))
+            .collect::<Vec<_>>();
+        for path in paths.iter() {
+            std::fs::write(path, fixture::map_bytes()).unwrap();
+        }
+        paths.push(dir.join("mh_map_decode_many_missing.map"));
+
+        let mut results = decode_many(paths.clone(), 2, 1).collect::<Vec<_>>();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(results.len(), 4);
+        for (path, map) in results {
+            if path.ends_with("mh_map_decode_many_missing.map") {
+                assert!(map.is_err());
+            } else {
+                assert_eq!(map.unwrap().units.len(), 1);
+                std::fs::remove_file(&path).unwrap();
+            }
+        }
+    }
+}
@@ -1,25 +1,81 @@
-use std::io::Read;
+use std::io::{Read, Write};
+
+use crate::error::MapError;
 
 pub fn read_u8<T: Read>(buffer: &mut T) -> anyhow::Result<u8> {
     let mut buf = [0; 1];
-    buffer.read(&mut buf)?;
+    buffer.read_exact(&mut buf)?;
     Ok(buf[0])
 }
 
+fn read_array<T: Read, const N: usize>(buffer: &mut T) -> anyhow::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    buffer.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// 地图文件中的整数都是小端，等同于 read_u16_le
 pub fn read_u16<T: Read>(buffer: &mut T) -> anyhow::Result<u16> {
-    let mut buf = [0u8; 2];
-    buffer.read(&mut buf)?;
-    Ok(u16::from_le_bytes(buf))
+    read_u16_le(buffer)
+}
+
+pub fn read_u16_le<T: Read>(buffer: &mut T) -> anyhow::Result<u16> {
+    Ok(u16::from_le_bytes(read_array(buffer)?))
+}
+
+/// JPEG 段长度之类的字段是大端
+pub fn read_u16_be<T: Read>(buffer: &mut T) -> anyhow::Result<u16> {
+    Ok(u16::from_be_bytes(read_array(buffer)?))
 }
 
+/// 地图文件中的整数都是小端，等同于 read_u32_le
 pub fn read_u32<T: Read>(file: &mut T) -> anyhow::Result<u32> {
-    let mut buffer: [u8; 4] = [0; 4];
-    file.read(&mut buffer)?;
-    Ok(u32::from_le_bytes(buffer))
+    read_u32_le(file)
+}
+
+pub fn read_u32_le<T: Read>(buffer: &mut T) -> anyhow::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(buffer)?))
+}
+
+pub fn read_u32_be<T: Read>(buffer: &mut T) -> anyhow::Result<u32> {
+    Ok(u32::from_be_bytes(read_array(buffer)?))
 }
 
 pub fn read_bytes<T: Read>(buffer: &mut T, size: usize) -> anyhow::Result<Vec<u8>> {
     let mut buf = vec![0u8; size];
-    buffer.read(&mut buf)?;
+    buffer.read_exact(&mut buf)?;
     Ok(buf)
 }
+
+/// 带溢出检查的乘法，what 用于错误信息
+pub fn checked_mul(a: u32, b: u32, what: &'static str) -> crate::Result<u32> {
+    a.checked_mul(b).ok_or(MapError::Overflow(what))
+}
+
+pub fn write_u32<T: Write>(buffer: &mut T, value: u32) -> anyhow::Result<()> {
+    buffer.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_order() {
+        let bytes = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(read_u16_le(&mut &bytes[..]).unwrap(), 0x3412);
+        assert_eq!(read_u16_be(&mut &bytes[..]).unwrap(), 0x1234);
+        assert_eq!(read_u32_le(&mut &bytes[..]).unwrap(), 0x7856_3412);
+        assert_eq!(read_u32_be(&mut &bytes[..]).unwrap(), 0x1234_5678);
+        assert_eq!(read_u32(&mut &bytes[..]).unwrap(), 0x7856_3412);
+    }
+
+    #[test]
+    fn short_reads_fail() {
+        let bytes = [0x12, 0x34, 0x56];
+        assert!(read_u32(&mut &bytes[..]).is_err());
+        assert!(read_bytes(&mut &bytes[..], 4).is_err());
+        assert!(read_u8(&mut &bytes[..0]).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use image::RgbaImage;
+
+use crate::mask::{pack_mask_bits, Mask, MaskKind};
+use crate::new_map::{Map, MapHeader, Unit};
+
+/// 从单元图片构造地图，构造出来的 Map 可以直接 encode 成文件
+///
+/// ```ignore
+/// let mut builder = MapBuilder::with_dimensions(640, 240);
+/// builder.set_tile(0, 0, left_jpeg).set_tile(0, 1, right_jpeg);
+/// let map = builder.build()?;
+/// ```
+pub struct MapBuilder {
+    width: u32,
+    height: u32,
+    tiles: Vec<(u32, u32, Vec<u8>)>,
+    masks: Vec<(u32, u32, RgbaImage)>,
+}
+
+impl MapBuilder {
+    pub fn with_dimensions(width: u32, height: u32) -> MapBuilder {
+        MapBuilder {
+            width,
+            height,
+            tiles: vec![],
+            masks: vec![],
+        }
+    }
+
+    /// 设置第 row 行第 col 列的单元，bytes 是完整的 JPEG，重复设置时后面的覆盖前面的
+    pub fn set_tile(&mut self, row: u32, col: u32, bytes: Vec<u8>) -> &mut Self {
+        self.tiles.retain(|(r, c, _)| (*r, *c) != (row, col));
+        self.tiles.push((row, col, bytes));
+        self
+    }
+
+    /// 在 (x, y) 处添加一个遮罩，图片中不透明的像素是遮挡区域，按添加的顺序绘制
+    pub fn add_mask(&mut self, x: u32, y: u32, image: RgbaImage) -> &mut Self {
+        self.masks.push((x, y, image));
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Map> {
+        let map_header = MapHeader::new(self.width, self.height)?;
+        let (rows, cols) = (map_header.rows, map_header.cols);
+
+        let mut units = Vec::with_capacity(self.tiles.len());
+        for (row, col, bytes) in self.tiles {
+            if row >= rows || col >= cols {
+                return Err(anyhow::anyhow!(
+                    "Tile ({}, {}) is outside the {}x{} grid",
+                    row,
+                    col,
+                    rows,
+                    cols
+                ));
+            }
+            if !bytes.starts_with(&[0xFF, 0xD8]) {
+                return Err(anyhow::anyhow!("Tile ({}, {}) is not a JPEG", row, col));
+            }
+            let mut unit = Unit::new("2GPJ", bytes.len() as u32, bytes);
+            unit.slot = map_header.slot_of(row, col);
+            units.push(unit);
+        }
+        units.sort_by_key(|unit| unit.slot);
+
+        let mut masks = Vec::with_capacity(self.masks.len());
+        for (index, (x, y, image)) in self.masks.into_iter().enumerate() {
+            let bits = image
+                .pixels()
+                .map(|pixel| if pixel[3] != 0 { 3 } else { 0 })
+                .collect::<Vec<u8>>();
+            let (width, height) = image.dimensions();
+            let data = pack_mask_bits(width, height, &bits)?;
+            masks.push(Mask::new(index, x, y, width, height, MaskKind::Occlusion, data)?);
+        }
+
+        Ok(Map::new(map_header, units, masks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::encode::encode;
+    use crate::fixture;
+    use crate::mask::decode_mask_bits;
+    use crate::new_map::decode_from_bytes;
+
+    #[test]
+    fn build_and_encode() {
+        let mut mask = RgbaImage::new(5, 2);
+        mask.put_pixel(1, 1, Rgba([0, 0, 0, 255]));
+
+        let mut builder = MapBuilder::with_dimensions(400, 240);
+        builder
+            .set_tile(0, 1, fixture::tile_jpeg(80, 240))
+            .add_mask(3, 4, mask);
+        let map = builder.build().unwrap();
+        assert_eq!((map.map_header.rows, map.map_header.cols), (1, 2));
+
+        let decoded = decode_from_bytes(&encode(&map).unwrap()).unwrap();
+        assert_eq!(decoded.units.len(), 1);
+        assert_eq!(decoded.units[0].slot, 1);
+        assert_eq!(
+            decode_mask_bits(&decoded.masks[0]).unwrap(),
+            vec![0, 0, 0, 0, 0, 0, 3, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! 地图和其他游戏资源共用的 LZO 压缩
+
+use rust_lzo::{LZOContext, LZOError};
+
+use crate::error::MapError;
+
+fn lzo_error(error: LZOError) -> MapError {
+    let reason = match error {
+        LZOError::INPUT_OVERRUN => "input overrun",
+        LZOError::OUTPUT_OVERRUN => "output overrun",
+        LZOError::LOOKBEHIND_OVERRUN => "lookbehind overrun",
+        LZOError::EOF_NOT_FOUND => "end of stream not found",
+        LZOError::INPUT_NOT_CONSUMED => "input not consumed",
+        _ => "unknown error",
+    };
+    MapError::Decompress(reason)
+}
+
+/// LZO1X 每个输入字节最多展开成 255 个输出字节 (长度字段每多一个 0 字节加 255)，
+/// 再留一点余量给流的开头和结尾
+pub(crate) fn max_decompressed_len(compressed_len: usize) -> usize {
+    compressed_len.saturating_mul(256).saturating_add(64)
+}
+
+/// 解压 LZO 数据，expected_len 是解压后的大小上限。
+/// 数据比 expected_len 短时返回实际解压出来的字节，长度是否符合要求由调用者检查。
+/// expected_len 通常是按文件中的宽高算出来的，预先分配的缓冲区不会超过 input 能解压出的最大长度
+pub fn lzo_decompress(input: &[u8], expected_len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut out = vec![0; expected_len.min(max_decompressed_len(input.len()))];
+    let (decompressed, error) = LZOContext::decompress_to_slice(input, &mut out);
+    if error != LZOError::OK {
+        return Err(lzo_error(error).into());
+    }
+    let len = decompressed.len();
+    out.truncate(len);
+    Ok(out)
+}
+
+/// LZO 压缩，编码地图时压缩遮罩数据使用
+pub fn lzo_compress(input: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut compressed = Vec::with_capacity(rust_lzo::worst_compress(input.len()));
+    let mut context = LZOContext::new();
+    if context.compress(input, &mut compressed) != LZOError::OK {
+        return Err(anyhow::anyhow!("Compress LZO data failed"));
+    }
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lzo_round_trip() {
+        let raw = (0..200u8).cycle().take(1000).collect::<Vec<u8>>();
+        let compressed = lzo_compress(&raw).unwrap();
+        assert_eq!(lzo_decompress(&compressed, raw.len()).unwrap(), raw);
+
+        let err = lzo_decompress(&compressed, 10).err().unwrap();
+        assert!(matches!(err.downcast_ref::<MapError>(), Some(MapError::Decompress(_))));
+
+        // 上限再大也只按压缩数据能解压出的长度分配
+        assert_eq!(lzo_decompress(&compressed, usize::MAX).unwrap(), raw);
+    }
+}
@@ -0,0 +1,183 @@
+use std::ops::Range;
+
+use crate::layout::Layout;
+use crate::new_map::{Map, MapHeader};
+use crate::render::{TILE_HEIGHT, TILE_WIDTH};
+
+/// 范围内单元拼起来的像素长度，包含最后一个单元时使用地图的真实边界
+fn span(range: &Range<u32>, count: u32, tile: u32, total: u32) -> u32 {
+    if range.end == count {
+        total - range.start * tile
+    } else {
+        (range.end - range.start) * tile
+    }
+}
+
+impl Map {
+    /// 截取 rows x cols 范围内的单元组成一个新的地图
+    ///
+    /// 新地图的索引表仍然是原文件中的偏移，完全落在范围内的遮罩会被保留并平移到新的坐标
+    pub fn crop_tiles(&self, rows: Range<u32>, cols: Range<u32>) -> anyhow::Result<Map> {
+        let header = &self.map_header;
+        if rows.is_empty() || cols.is_empty() || rows.end > header.rows || cols.end > header.cols {
+            return Err(anyhow::anyhow!(
+                "Invalid crop {:?} x {:?} for a {}x{} tile grid",
+                rows,
+                cols,
+                header.rows,
+                header.cols
+            ));
+        }
+
+        let width = span(&cols, header.cols, TILE_WIDTH, header.width);
+        let height = span(&rows, header.rows, TILE_HEIGHT, header.height);
+        let new_rows = rows.end - rows.start;
+        let new_cols = cols.end - cols.start;
+
+        let mut new_header = MapHeader {
+            magic: header.magic,
+            version: header.version,
+            width,
+            height,
+            header_size: header.header_size,
+            dimension_format: header.dimension_format,
+            map_index_list: vec![0; (new_rows * new_cols) as usize],
+            rows: new_rows,
+            cols: new_cols,
+            index_size: new_rows * new_cols,
+            tile_order: header.tile_order,
+        };
+        for row in rows.clone() {
+            for col in cols.clone() {
+                let slot = new_header.slot_of(row - rows.start, col - cols.start);
+                new_header.map_index_list[slot] = header.offset_of(row, col).unwrap_or(0);
+            }
+        }
+
+        let left = cols.start * TILE_WIDTH;
+        let top = rows.start * TILE_HEIGHT;
+        // 保留下来的遮罩在原遮罩表中的下标
+        let kept = (0..self.masks.len())
+            .filter(|index| {
+                let mask = &self.masks[*index];
+                mask.x >= left
+                    && mask.y >= top
+                    && mask.x.saturating_add(mask.width) <= left + width
+                    && mask.y.saturating_add(mask.height) <= top + height
+            })
+            .collect::<Vec<_>>();
+        let masks = kept
+            .iter()
+            .map(|index| {
+                let mut mask = self.masks[*index].clone();
+                mask.x -= left;
+                mask.y -= top;
+                mask.raw = None;
+                mask
+            })
+            .collect();
+
+        let units = self
+            .units
+            .iter()
+            .filter_map(|unit| {
+                let (row, col) = header.position_of(unit.slot);
+                if !rows.contains(&row) || !cols.contains(&col) {
+                    return None;
+                }
+                let mut unit = unit.clone();
+                unit.slot = new_header.slot_of(row - rows.start, col - cols.start);
+                // 遮罩下标换成在新遮罩表中的位置，被去掉的遮罩不再引用
+                unit.mask_indices = unit
+                    .mask_indices
+                    .iter()
+                    .filter_map(|index| kept.iter().position(|k| *k == *index as usize))
+                    .map(|position| position as u32)
+                    .collect();
+                // 原始字节的偏移是相对于原文件的，裁剪后没有意义
+                unit.raw = None;
+                Some(unit)
+            })
+            .collect();
+
+        Ok(Map {
+            map_header: new_header,
+            units,
+            masks,
+            mask_table_flag: self.mask_table_flag,
+            layout: Layout::default(),
+            warnings: vec![],
+            partial: self.partial,
+            raw_gaps: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::MapBuilder;
+    use crate::fixture;
+    use crate::mask::{Mask, MaskKind};
+
+    /// 400x500 的地图，3 行 2 列，右边一列和最下面一行不满一个单元
+    fn sample_map() -> Map {
+        let mut builder = MapBuilder::with_dimensions(400, 500);
+        builder.set_tile(0, 0, fixture::tile_jpeg(320, 240));
+        builder.set_tile(1, 1, fixture::tile_jpeg(80, 240));
+        builder.set_tile(2, 1, fixture::tile_jpeg(80, 20));
+        let mut map = builder.build().unwrap();
+
+        let mask = |index, x, y| Mask::new(index, x, y, 8, 4, MaskKind::Occlusion, vec![0; 8]);
+        map.masks = vec![
+            mask(0, 10, 10).unwrap(),
+            mask(1, 330, 250).unwrap(),
+            // 跨过裁剪范围的左边界
+            mask(2, 316, 250).unwrap(),
+            // 贴着地图的右下角
+            mask(3, 392, 496).unwrap(),
+        ];
+        for unit in map.units.iter_mut() {
+            unit.mask_indices = match unit.slot {
+                3 => vec![1, 2],
+                5 => vec![0, 3],
+                _ => vec![],
+            };
+        }
+        map
+    }
+
+    #[test]
+    fn crop_to_the_map_edge_uses_real_size() {
+        let map = sample_map();
+        let cropped = map.crop_tiles(1..3, 1..2).unwrap();
+        let header = &cropped.map_header;
+        assert_eq!((header.rows, header.cols), (2, 1));
+        assert_eq!((header.width, header.height), (80, 260));
+
+        let inner = map.crop_tiles(0..1, 0..1).unwrap();
+        assert_eq!((inner.map_header.width, inner.map_header.height), (320, 240));
+    }
+
+    #[test]
+    fn crop_keeps_masks_inside_and_remaps_indices() {
+        let cropped = sample_map().crop_tiles(1..3, 1..2).unwrap();
+        let positions = cropped.masks.iter().map(|mask| (mask.x, mask.y)).collect::<Vec<_>>();
+        assert_eq!(positions, vec![(10, 10), (72, 256)]);
+
+        let indices = cropped
+            .units
+            .iter()
+            .map(|unit| (unit.slot, unit.mask_indices.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(indices, vec![(0, vec![0]), (1, vec![1])]);
+    }
+
+    #[test]
+    fn crop_rejects_invalid_ranges() {
+        let map = sample_map();
+        assert!(map.crop_tiles(1..1, 0..1).is_err());
+        assert!(map.crop_tiles(2..4, 0..1).is_err());
+        assert!(map.crop_tiles(0..1, 0..3).is_err());
+    }
+}
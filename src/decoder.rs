@@ -0,0 +1,19 @@
+//! 单元图片的解码器，需要更快的 JPEG 解码 (比如 turbojpeg) 时可以自己实现 TileDecoder
+
+use image::RgbaImage;
+
+/// 把单元数据 (修复过的完整 JPEG 或 PNG) 解码成图片。
+/// render_parallel 之类的会在多个线程中共用同一个解码器，所以要求 Sync
+pub trait TileDecoder: Sync {
+    fn decode(&self, bytes: &[u8]) -> anyhow::Result<RgbaImage>;
+}
+
+/// 默认的解码器，使用 image crate
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImageDecoder;
+
+impl TileDecoder for ImageDecoder {
+    fn decode(&self, bytes: &[u8]) -> anyhow::Result<RgbaImage> {
+        Ok(image::load_from_memory(bytes)?.into_rgba8())
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::new_map::{Map, Unit};
+
+/// 两个地图之间的差异
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MapDiff {
+    /// 宽高不同时为 Some((旧的宽高, 新的宽高))
+    pub dimensions: Option<((u32, u32), (u32, u32))>,
+    /// 数据不同的单元位置，只在其中一个地图中存在的单元也算
+    pub changed_slots: Vec<usize>,
+}
+
+impl MapDiff {
+    /// 两个地图完全一样
+    pub fn is_empty(&self) -> bool {
+        self.dimensions.is_none() && self.changed_slots.is_empty()
+    }
+}
+
+/// 逐个单元比较两个地图，比较的是单元的原始数据，不解码图片
+pub fn diff(a: &Map, b: &Map) -> MapDiff {
+    let size_a = (a.map_header.width, a.map_header.height);
+    let size_b = (b.map_header.width, b.map_header.height);
+    let dimensions = if size_a != size_b {
+        Some((size_a, size_b))
+    } else {
+        None
+    };
+
+    let units_a = by_slot(a);
+    let units_b = by_slot(b);
+    let slots = units_a.keys().chain(units_b.keys()).copied().collect::<BTreeSet<_>>();
+
+    let changed_slots = slots
+        .into_iter()
+        .filter(|slot| match (units_a.get(slot), units_b.get(slot)) {
+            (Some(x), Some(y)) => x.unit_data != y.unit_data,
+            _ => true,
+        })
+        .collect();
+
+    MapDiff {
+        dimensions,
+        changed_slots,
+    }
+}
+
+fn by_slot(map: &Map) -> HashMap<usize, &Unit> {
+    map.units.iter().map(|unit| (unit.slot, unit)).collect()
+}
+
+/// 64 位 FNV-1a，结果和平台、Rust 版本无关，可以保存下来和别的机器上算出来的比较
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+impl Map {
+    /// 每个单元原始数据的哈希，按 slot 排序。
+    /// 做增量更新时比较新旧两份列表，只需要分发哈希不同的单元
+    pub fn tile_hashes(&self) -> Vec<(usize, u64)> {
+        let mut hashes = self
+            .units
+            .iter()
+            .map(|unit| (unit.slot, fnv1a(&unit.unit_data)))
+            .collect::<Vec<_>>();
+        hashes.sort_by_key(|(slot, _)| *slot);
+        hashes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::new_map::decode_from_bytes;
+
+    #[test]
+    fn diff_reports_dimensions_and_slots() {
+        let mut a = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let mut b = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert!(diff(&a, &b).is_empty());
+
+        // 两边都有且相同的 3，只在 a 中的 2，只在 b 中的 1，数据不同的 0
+        let unit = a.units[0].clone();
+        let with_slot = |slot| Unit {
+            slot,
+            ..unit.clone()
+        };
+        a.units.extend([with_slot(2), with_slot(3)]);
+        b.units.extend([with_slot(1), with_slot(3)]);
+        b.units[0].unit_data.push(0);
+        b.map_header.width = 640;
+
+        let result = diff(&a, &b);
+        assert_eq!(result.dimensions, Some(((320, 240), (640, 240))));
+        assert_eq!(result.changed_slots, vec![0, 1, 2]);
+        assert!(!result.is_empty());
+    }
+
+    #[test]
+    fn tile_hashes_follow_unit_data() {
+        let a = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let mut b = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert_eq!(a.tile_hashes(), b.tile_hashes());
+
+        b.units[0].unit_data.push(0);
+        assert_eq!(a.tile_hashes()[0].0, b.tile_hashes()[0].0);
+        assert_ne!(a.tile_hashes()[0].1, b.tile_hashes()[0].1);
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    }
+}
@@ -0,0 +1,219 @@
+use std::io::{Cursor, Seek, SeekFrom, Write};
+
+use crate::buffer_utils;
+use crate::error::MapError;
+use crate::mask::compress_mask;
+use crate::new_map::{DimensionFormat, Map};
+
+/// 当前写入位置相对于起点的偏移，文件中的偏移都是 u32
+fn offset_from<W: Seek>(writer: &mut W, base: u64) -> anyhow::Result<u32> {
+    let offset = writer.stream_position()? - base;
+    Ok(u32::try_from(offset).map_err(|_| MapError::Overflow("file offset"))?)
+}
+
+/// 写一串 u32 占位，返回占位开始的位置，等数据写完后再回填
+fn reserve<W: Write + Seek>(writer: &mut W, count: usize) -> anyhow::Result<u64> {
+    let position = writer.stream_position()?;
+    for _ in 0..count {
+        buffer_utils::write_u32(writer, 0)?;
+    }
+    Ok(position)
+}
+
+fn backfill<W: Write + Seek>(writer: &mut W, position: u64, values: &[u32]) -> anyhow::Result<()> {
+    writer.seek(SeekFrom::Start(position))?;
+    for value in values {
+        buffer_utils::write_u32(writer, *value)?;
+    }
+    Ok(())
+}
+
+/// 把地图编码成 M1.0 格式写入 writer
+///
+/// 索引表和遮罩偏移表引用的是后面的数据，所以先写占位，写完数据后再 seek 回去回填。
+/// GEPJ 单元在解码时已经修复成完整的 JPEG，所以统一写成 2GPJ。
+/// 索引表中没有单元的位置也要有偏移，这些位置写一个占位单元: 前缀数量 0、
+/// 4 个 0 字节的类型标识和大小 0，读取时因为类型不认识会被跳过。
+/// 单元的 slot 超出索引表 (index_size) 时返回错误
+pub fn encode_to<W: Write + Seek>(map: &Map, writer: &mut W) -> anyhow::Result<()> {
+    let header = &map.map_header;
+    let mut units_by_slot = vec![None; header.index_size as usize];
+    for unit in map.units.iter() {
+        let entry = units_by_slot.get_mut(unit.slot).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unit slot {} is outside the index table ({} entries)",
+                unit.slot,
+                header.index_size
+            )
+        })?;
+        *entry = Some(unit);
+    }
+    let base = writer.stream_position()?;
+
+    writer.write_all(b"0.1M")?;
+    buffer_utils::write_u32(writer, header.width)?;
+    buffer_utils::write_u32(writer, header.height)?;
+    let index_position = reserve(writer, header.index_size as usize)?;
+
+    buffer_utils::write_u32(writer, map.mask_table_flag)?;
+    buffer_utils::write_u32(writer, map.masks.len() as u32)?;
+    let mask_table_position = reserve(writer, map.masks.len())?;
+
+    let mut mask_offsets = Vec::with_capacity(map.masks.len());
+    for mask in map.masks.iter() {
+        mask_offsets.push(offset_from(writer, base)?);
+        let compressed = compress_mask(mask)?;
+        for value in [mask.x, mask.y, mask.width, mask.height, compressed.len() as u32] {
+            buffer_utils::write_u32(writer, value)?;
+        }
+        writer.write_all(&compressed)?;
+    }
+
+    let mut unit_offsets = vec![0u32; header.index_size as usize];
+    for (slot, offset) in unit_offsets.iter_mut().enumerate() {
+        *offset = offset_from(writer, base)?;
+        match units_by_slot[slot] {
+            Some(unit) => {
+                // 单元前缀: 和单元重叠的遮罩下标
+                buffer_utils::write_u32(writer, unit.mask_indices.len() as u32)?;
+                for index in unit.mask_indices.iter() {
+                    buffer_utils::write_u32(writer, *index)?;
+                }
+                let flag = if unit.unit_flag == "GEPJ" {
+                    "2GPJ"
+                } else {
+                    unit.unit_flag.as_str()
+                };
+                if flag.len() != 4 {
+                    return Err(anyhow::anyhow!("Invalid unit flag {:?} at slot {}", flag, slot));
+                }
+                writer.write_all(flag.as_bytes())?;
+                buffer_utils::write_u32(writer, unit.unit_data.len() as u32)?;
+                writer.write_all(&unit.unit_data)?;
+            }
+            None => {
+                buffer_utils::write_u32(writer, 0)?;
+                writer.write_all(&[0; 4])?;
+                buffer_utils::write_u32(writer, 0)?;
+            }
+        }
+    }
+    let end = writer.stream_position()?;
+
+    backfill(writer, index_position, &unit_offsets)?;
+    backfill(writer, mask_table_position, &mask_offsets)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// 用 DecodeOptions::preserve_raw 解码时保留的原始字节重新生成文件，结果和原文件逐字节相同
+///
+/// 文件头和遮罩表按解析出的字段重新写，其余部分都是原样复制的，
+/// 所以解码后对单元或遮罩的修改不会体现在结果中。有单元或遮罩没有原始字节时返回错误
+pub fn encode_raw(map: &Map) -> anyhow::Result<Vec<u8>> {
+    let header = &map.map_header;
+    let mut bytes = header.magic.to_vec();
+    match header.dimension_format {
+        DimensionFormat::U32 => {
+            buffer_utils::write_u32(&mut bytes, header.width)?;
+            buffer_utils::write_u32(&mut bytes, header.height)?;
+        }
+        DimensionFormat::U16 => {
+            bytes.extend_from_slice(&(header.width as u16).to_le_bytes());
+            bytes.extend_from_slice(&(header.height as u16).to_le_bytes());
+        }
+    }
+    for offset in header.map_index_list.iter() {
+        buffer_utils::write_u32(&mut bytes, *offset)?;
+    }
+
+    let mut spans = Vec::with_capacity(map.masks.len() + map.units.len() + map.raw_gaps.len());
+    buffer_utils::write_u32(&mut bytes, map.mask_table_flag)?;
+    buffer_utils::write_u32(&mut bytes, map.masks.len() as u32)?;
+    for mask in map.masks.iter() {
+        let raw = mask
+            .raw()
+            .ok_or_else(|| anyhow::anyhow!("Mask {} has no raw bytes", mask.index()))?;
+        let offset = u32::try_from(raw.offset).map_err(|_| MapError::Overflow("mask offset"))?;
+        buffer_utils::write_u32(&mut bytes, offset)?;
+        spans.push(raw);
+    }
+    for unit in map.units.iter() {
+        let raw = unit
+            .raw
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Unit {} has no raw bytes", unit.slot))?;
+        spans.push(raw);
+    }
+    spans.extend(map.raw_gaps.iter());
+
+    for span in spans {
+        let range = span.range();
+        let (start, end) = (range.start as usize, range.end as usize);
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[start..end].copy_from_slice(&span.bytes);
+    }
+    Ok(bytes)
+}
+
+/// 把地图编码成 M1.0 格式的字节
+pub fn encode(map: &Map) -> anyhow::Result<Vec<u8>> {
+    let mut cursor = Cursor::new(vec![]);
+    encode_to(map, &mut cursor)?;
+    Ok(cursor.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::mask::decode_mask_bits;
+    use crate::new_map::{decode_from_bytes, decode_from_bytes_with_options, DecodeOptions};
+
+    #[test]
+    fn encode_raw_is_byte_exact() {
+        let mut bytes = fixture::map_bytes();
+        // 文件末尾多出来的字节也要保留
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let options = DecodeOptions::default().preserve_raw(true);
+        let map = decode_from_bytes_with_options(&bytes, &options).unwrap();
+        assert_eq!(map.raw_gaps.len(), 1);
+        assert_eq!(encode_raw(&map).unwrap(), bytes);
+
+        let map = decode_from_bytes(&bytes).unwrap();
+        assert!(encode_raw(&map).is_err());
+    }
+
+    #[test]
+    fn encode_fills_missing_slots_and_rejects_stray_units() {
+        let mut builder = crate::builder::MapBuilder::with_dimensions(640, 240);
+        builder.set_tile(0, 1, fixture::tile_jpeg(320, 240));
+        let mut map = builder.build().unwrap();
+
+        let decoded = decode_from_bytes(&encode(&map).unwrap()).unwrap();
+        assert_eq!(decoded.map_header.map_index_list.len(), 2);
+        assert_eq!(decoded.units.len(), 1);
+        assert_eq!(decoded.units[0].slot, 1);
+
+        map.units[0].slot = 2;
+        assert!(encode(&map).is_err());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let decoded = decode_from_bytes(&encode(&map).unwrap()).unwrap();
+
+        assert_eq!(decoded.map_header.width, map.map_header.width);
+        assert_eq!(decoded.map_header.height, map.map_header.height);
+        assert_eq!(decoded.units.len(), 1);
+        assert_eq!(decoded.units[0].unit_data, map.units[0].unit_data);
+        assert_eq!(decoded.masks.len(), 1);
+        assert_eq!(
+            decode_mask_bits(&decoded.masks[0]).unwrap(),
+            decode_mask_bits(&map.masks[0]).unwrap()
+        );
+    }
+}
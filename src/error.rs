@@ -0,0 +1,77 @@
+use std::fmt;
+
+/// 错误类型为 MapError 的结果，`mh_map::Result<T>`。
+/// 目前只有不涉及 IO 和图片解码的函数返回它，其余的仍然返回 anyhow::Result
+pub type Result<T> = std::result::Result<T, MapError>;
+
+/// 解析地图时的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// 用文件中的字段计算大小或位置时溢出，一般是文件损坏或者被刻意构造过
+    Overflow(&'static str),
+    /// 遮罩解压出来的字节数和按宽高算出来的不一致
+    MaskSizeMismatch {
+        expected: usize,
+        got: usize,
+        offset: u32,
+    },
+    /// LZO 数据解压失败，参数是失败原因
+    Decompress(&'static str),
+    /// 遮罩头中的宽高超过 MAX_DIMENSION，一般是文件损坏
+    MaskTooLarge {
+        width: u32,
+        height: u32,
+        offset: u32,
+    },
+    /// 单元头中记录的大小超过了文件剩下的字节数
+    UnitTooLarge {
+        slot: usize,
+        size: u32,
+        available: u64,
+    },
+    /// 解码出的单元和遮罩加起来超过了按文件大小算出的上限，
+    /// 一般是索引表或遮罩表中很多项指向同一段数据
+    AllocationLimit {
+        requested: u64,
+        limit: u64,
+    },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Overflow(what) => write!(f, "Arithmetic overflow while computing {}", what),
+            MapError::MaskSizeMismatch {
+                expected,
+                got,
+                offset,
+            } => write!(
+                f,
+                "Mask at offset {} decompressed to {} bytes, expected {}",
+                offset, got, expected
+            ),
+            MapError::Decompress(reason) => write!(f, "LZO decompression failed: {}", reason),
+            MapError::MaskTooLarge {
+                width,
+                height,
+                offset,
+            } => write!(f, "Mask at offset {} is too large: {}x{}", offset, width, height),
+            MapError::UnitTooLarge {
+                slot,
+                size,
+                available,
+            } => write!(
+                f,
+                "Unit {} declares {} bytes but only {} bytes remain in the file",
+                slot, size, available
+            ),
+            MapError::AllocationLimit { requested, limit } => write!(
+                f,
+                "Decoded data would take {} bytes, more than the limit of {} bytes",
+                requested, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
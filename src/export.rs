@@ -0,0 +1,536 @@
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+#[cfg(feature = "webp")]
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    imageops::{self, FilterType},
+    ColorType, DynamicImage, ImageEncoder, Rgba, RgbaImage,
+};
+
+use crate::decoder::{ImageDecoder, TileDecoder};
+use crate::mask::{decode_mask_cells, MaskCell};
+use crate::new_map::Map;
+use crate::render::{
+    load_tile, render, render_row_strip, render_with_decoder, tile_rect, units_by_row,
+    RenderOptions, TILE_HEIGHT, TILE_WIDTH,
+};
+
+/// 导出瓦片时使用的图片格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileFormat {
+    Png,
+    /// 无损 WebP，需要开启 `webp` 特性
+    #[cfg(feature = "webp")]
+    WebP,
+    /// JPEG, 参数为质量 (1-100, 超出范围会被编码器截断)
+    Jpeg(u8),
+}
+
+impl TileFormat {
+    /// 文件扩展名
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TileFormat::Png => "png",
+            #[cfg(feature = "webp")]
+            TileFormat::WebP => "webp",
+            TileFormat::Jpeg(_) => "jpg",
+        }
+    }
+}
+
+/// 按指定格式编码一张图片
+pub fn write_image<W: Write>(
+    image: &DynamicImage,
+    writer: W,
+    format: TileFormat,
+) -> anyhow::Result<()> {
+    let (width, height) = (image.width(), image.height());
+    match format {
+        TileFormat::Png => {
+            let rgba = image.to_rgba8();
+            PngEncoder::new(writer).write_image(&rgba, width, height, ColorType::Rgba8)?;
+        }
+        #[cfg(feature = "webp")]
+        TileFormat::WebP => {
+            let rgba = image.to_rgba8();
+            WebPEncoder::new_with_quality(writer, WebPQuality::lossless()).encode(
+                &rgba,
+                width,
+                height,
+                ColorType::Rgba8,
+            )?;
+        }
+        TileFormat::Jpeg(quality) => {
+            // JPEG 没有透明通道
+            let rgb = image.to_rgb8();
+            let mut encoder = JpegEncoder::new_with_quality(writer, quality);
+            encoder.encode(&rgb, width, height, ColorType::Rgb8)?;
+        }
+    }
+    Ok(())
+}
+
+/// 导出单元图片的选项
+#[derive(Debug, Clone, Copy)]
+pub struct TileExportOptions {
+    pub format: TileFormat,
+    /// 把地图边上不满 320x240 的单元补齐到完整大小，给要求所有单元一样大的引擎使用
+    pub pad_edges: bool,
+    /// 补出来的部分的颜色，默认透明 (JPEG 没有透明通道，会变成黑色)
+    pub pad_color: Rgba<u8>,
+}
+
+impl TileExportOptions {
+    pub fn new(format: TileFormat) -> TileExportOptions {
+        TileExportOptions {
+            format,
+            pad_edges: false,
+            pad_color: Rgba([0, 0, 0, 0]),
+        }
+    }
+}
+
+/// 把每一个单元导出为单独的图片，文件名为 `{行}_{列}.{扩展名}`
+pub fn export_tiles<P: AsRef<Path>>(map: &Map, out_dir: P, format: TileFormat) -> anyhow::Result<()> {
+    export_tiles_with_options(map, out_dir, &TileExportOptions::new(format))?;
+    Ok(())
+}
+
+/// 和 export_tiles 一样，按 options 导出单元图片。返回每个单元的 slot 和它在地图中的有效区域
+/// (x, y, width, height)，补齐边上的单元时图片比有效区域大，多出来的右边和下边不属于地图
+pub fn export_tiles_with_options<P: AsRef<Path>>(
+    map: &Map,
+    out_dir: P,
+    options: &TileExportOptions,
+) -> anyhow::Result<Vec<(usize, (u32, u32, u32, u32))>> {
+    write_tiles(map, out_dir.as_ref(), options, &ImageDecoder)
+}
+
+/// 和 export_tiles_with_options 一样，但是用指定的解码器解码单元
+pub fn export_tiles_with_decoder<P: AsRef<Path>>(
+    map: &Map,
+    out_dir: P,
+    options: &TileExportOptions,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<Vec<(usize, (u32, u32, u32, u32))>> {
+    write_tiles(map, out_dir.as_ref(), options, decoder)
+}
+
+fn write_tiles(
+    map: &Map,
+    out_dir: &Path,
+    options: &TileExportOptions,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<Vec<(usize, (u32, u32, u32, u32))>> {
+    fs::create_dir_all(out_dir)?;
+
+    let format = options.format;
+    let mut clips = Vec::with_capacity(map.units.len());
+    for unit in map.units.iter() {
+        let (row, col) = map.map_header.position_of(unit.slot);
+        let mut image = load_tile(unit, decoder)?;
+        if options.pad_edges && (image.width(), image.height()) != (TILE_WIDTH, TILE_HEIGHT) {
+            let mut padded = RgbaImage::from_pixel(TILE_WIDTH, TILE_HEIGHT, options.pad_color);
+            imageops::replace(&mut padded, &image.to_rgba8(), 0, 0);
+            image = Cow::Owned(DynamicImage::ImageRgba8(padded));
+        }
+        let path = out_dir.join(format!("{}_{}.{}", row, col, format.extension()));
+        let writer = BufWriter::new(File::create(path)?);
+        write_image(&image, writer, format)?;
+        clips.push((unit.slot, tile_rect(map, unit.slot)));
+    }
+    Ok(clips)
+}
+
+/// 按单元行导出横条图片，文件名为 `row_{行}.png`，每张宽度是地图的宽度，高度是一个单元
+/// (最后一行裁剪到地图范围内)。一次只渲染一行，方便网页从上到下逐条加载
+pub fn export_rows<P: AsRef<Path>>(map: &Map, out_dir: P) -> anyhow::Result<()> {
+    export_rows_with_decoder(map, out_dir, &ImageDecoder)
+}
+
+/// 和 export_rows 一样，但是用指定的解码器解码单元
+pub fn export_rows_with_decoder<P: AsRef<Path>>(
+    map: &Map,
+    out_dir: P,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<()> {
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+
+    let mut strip = vec![];
+    for (row, units) in units_by_row(map).iter().enumerate() {
+        let height = render_row_strip(map, row as u32, units, decoder, &mut strip)?;
+        let writer = BufWriter::new(File::create(out_dir.join(format!("row_{}.png", row)))?);
+        let width = map.map_header.width;
+        PngEncoder::new(writer).write_image(&strip, width, height, ColorType::Rgba8)?;
+    }
+    Ok(())
+}
+
+/// 导出 Deep Zoom (DZI) 金字塔，给 OpenSeadragon 之类的查看器使用
+///
+/// 在 out_dir 下生成 `map.dzi` 描述文件和 `map_files/{层级}/{列}_{行}.jpg` 瓦片，
+/// 最高层级是原始分辨率，每往下一级宽高减半，直到 1x1。
+/// DZI 的瓦片是正方形的，和地图 320x240 的单元对不上，所以每一层都是从拼好的图片上切出来的
+pub fn export_deepzoom<P: AsRef<Path>>(map: &Map, out_dir: P, tile_size: u32) -> anyhow::Result<()> {
+    export_deepzoom_with_decoder(map, out_dir, tile_size, &ImageDecoder)
+}
+
+/// 和 export_deepzoom 一样，但是用指定的解码器解码单元
+pub fn export_deepzoom_with_decoder<P: AsRef<Path>>(
+    map: &Map,
+    out_dir: P,
+    tile_size: u32,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<()> {
+    if tile_size == 0 {
+        return Err(anyhow::anyhow!("Tile size must be at least 1"));
+    }
+    let out_dir = out_dir.as_ref();
+    let files_dir = out_dir.join("map_files");
+
+    let mut image = render_with_decoder(map, &RenderOptions::default(), decoder)?.image;
+    let (width, height) = image.dimensions();
+    let mut max_level = 0;
+    while (1u64 << max_level) < width.max(height) as u64 {
+        max_level += 1;
+    }
+
+    for level in (0..=max_level).rev() {
+        let level_dir = files_dir.join(level.to_string());
+        fs::create_dir_all(&level_dir)?;
+
+        let (level_width, level_height) = image.dimensions();
+        for row in 0..level_height.div_ceil(tile_size) {
+            for col in 0..level_width.div_ceil(tile_size) {
+                let x = col * tile_size;
+                let y = row * tile_size;
+                let tile = imageops::crop_imm(
+                    &image,
+                    x,
+                    y,
+                    tile_size.min(level_width - x),
+                    tile_size.min(level_height - y),
+                )
+                .to_image();
+                let path = level_dir.join(format!("{}_{}.jpg", col, row));
+                let writer = BufWriter::new(File::create(path)?);
+                write_image(&DynamicImage::ImageRgba8(tile), writer, TileFormat::Jpeg(90))?;
+            }
+        }
+
+        if level > 0 {
+            let next_width = ((level_width + 1) / 2).max(1);
+            let next_height = ((level_height + 1) / 2).max(1);
+            image = imageops::resize(&image, next_width, next_height, FilterType::Triangle);
+        }
+    }
+
+    let descriptor = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" TileSize=\"{}\" Overlap=\"0\" Format=\"jpg\">\n\
+         <Size Width=\"{}\" Height=\"{}\"/>\n\
+         </Image>\n",
+        tile_size, width, height
+    );
+    fs::write(out_dir.join("map.dzi"), descriptor)?;
+    Ok(())
+}
+
+/// 导出给 Tiled 编辑器使用的 `map.tmx` 和图块集图片 `map_tileset.png`
+///
+/// 图块集是拼好的整张地图，补齐到整数个单元大小，所以第 n 个图块正好是第 n 个单元的位置。
+/// 遮罩导出为对象层 `masks` 中的矩形
+pub fn export_tmx<P: AsRef<Path>>(map: &Map, out_dir: P) -> anyhow::Result<()> {
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+
+    let header = &map.map_header;
+    let mut tileset = RgbaImage::new(header.cols * TILE_WIDTH, header.rows * TILE_HEIGHT);
+    imageops::replace(&mut tileset, &render(map)?, 0, 0);
+    let writer = BufWriter::new(File::create(out_dir.join("map_tileset.png"))?);
+    write_image(&DynamicImage::ImageRgba8(tileset), writer, TileFormat::Png)?;
+
+    fs::write(out_dir.join("map.tmx"), tmx_document(map, "map_tileset.png"))?;
+    Ok(())
+}
+
+/// TMX 文件内容，image_source 是图块集图片相对于 TMX 文件的路径
+fn tmx_document(map: &Map, image_source: &str) -> String {
+    let header = &map.map_header;
+    let (rows, cols) = (header.rows, header.cols);
+
+    let mut tmx = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <map version=\"1.10\" orientation=\"orthogonal\" renderorder=\"right-down\" \
+         width=\"{cols}\" height=\"{rows}\" tilewidth=\"{tw}\" tileheight=\"{th}\" \
+         infinite=\"0\" nextlayerid=\"3\" nextobjectid=\"{next}\">\n\
+         <tileset firstgid=\"1\" name=\"map\" tilewidth=\"{tw}\" tileheight=\"{th}\" \
+         tilecount=\"{count}\" columns=\"{cols}\">\n\
+         <image source=\"{image_source}\" width=\"{iw}\" height=\"{ih}\"/>\n\
+         </tileset>\n\
+         <layer id=\"1\" name=\"tiles\" width=\"{cols}\" height=\"{rows}\">\n\
+         <data encoding=\"csv\">\n",
+        cols = cols,
+        rows = rows,
+        tw = TILE_WIDTH,
+        th = TILE_HEIGHT,
+        next = map.masks.len() + 1,
+        count = rows * cols,
+        image_source = image_source,
+        iw = cols * TILE_WIDTH,
+        ih = rows * TILE_HEIGHT,
+    );
+
+    // 图块集和地图的网格一一对应，gid 从 1 开始
+    let gids = (0..rows)
+        .map(|row| {
+            (0..cols)
+                .map(|col| (row * cols + col + 1).to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect::<Vec<_>>();
+    tmx.push_str(&gids.join(",\n"));
+    tmx.push_str("\n</data>\n</layer>\n<objectgroup id=\"2\" name=\"masks\">\n");
+
+    for (id, mask) in map.masks.iter().enumerate() {
+        tmx.push_str(&format!(
+            "<object id=\"{}\" name=\"mask {}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>\n",
+            id + 1,
+            mask.index(),
+            mask.x(),
+            mask.y(),
+            mask.width(),
+            mask.height()
+        ));
+    }
+
+    tmx.push_str("</objectgroup>\n</map>\n");
+    tmx
+}
+
+/// 把 (x, y, w, h) 裁剪到 width x height 的地图范围内，完全在范围外时返回 None。
+/// 遮罩的坐标来自文件，可能大到相加溢出，所以用 u64 计算
+fn clip_rect(
+    width: u32,
+    height: u32,
+    (x, y, w, h): (u32, u32, u32, u32),
+) -> Option<(u32, u32, u32, u32)> {
+    let right = (x as u64 + w as u64).min(width as u64);
+    let bottom = (y as u64 + h as u64).min(height as u64);
+    if x as u64 >= right || y as u64 >= bottom {
+        return None;
+    }
+    Some((x, y, (right - x as u64) as u32, (bottom - y as u64) as u32))
+}
+
+/// SVG 中的一个矩形
+fn svg_rect((x, y, w, h): (u32, u32, u32, u32)) -> String {
+    format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>\n", x, y, w, h)
+}
+
+/// 把遮罩导出成 SVG，每个遮罩中每一行连续的遮挡格子是一个 `<rect>`，
+/// 解码失败的遮罩用它的外框代替。超出地图的部分会被裁剪掉，和 render_mask_layer 一致
+pub fn masks_to_svg(map: &Map) -> String {
+    let header = &map.map_header;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n",
+        w = header.width,
+        h = header.height
+    );
+
+    for mask in map.masks.iter() {
+        svg.push_str(&format!("<g data-mask=\"{}\" fill=\"black\">\n", mask.index()));
+        match decode_mask_cells(mask) {
+            Ok(grid) => {
+                for y in 0..grid.height {
+                    let mut x = 0;
+                    while x < grid.width {
+                        if grid.get(x, y) != Some(MaskCell::Full) {
+                            x += 1;
+                            continue;
+                        }
+                        let start = x;
+                        while grid.get(x, y) == Some(MaskCell::Full) {
+                            x += 1;
+                        }
+                        // 相加溢出的已经在地图外面了
+                        let left = mask.x().checked_add(start);
+                        let top = mask.y().checked_add(y);
+                        if let (Some(left), Some(top)) = (left, top) {
+                            let run = (left, top, x - start, 1);
+                            if let Some(rect) = clip_rect(header.width, header.height, run) {
+                                svg.push_str(&svg_rect(rect));
+                            }
+                        }
+                    }
+                }
+            }
+            Err(_) => {
+                let outline = (mask.x(), mask.y(), mask.width(), mask.height());
+                if let Some(rect) = clip_rect(header.width, header.height, outline) {
+                    svg.push_str(&svg_rect(rect));
+                }
+            }
+        }
+        svg.push_str("</g>\n");
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::mask::{Mask, MaskKind};
+    use crate::new_map::decode_from_bytes;
+
+    #[test]
+    fn masks_to_svg_works() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let svg = masks_to_svg(&map);
+        let (x, y, width, height) = fixture::MASK;
+        // 遮罩的每一行都是满的，所以每行一个 rect
+        assert_eq!(svg.matches("<rect").count(), height as usize);
+        assert!(svg.contains(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"1\"/>",
+            x, y, width
+        )));
+    }
+
+    #[test]
+    fn export_rows_writes_one_png_per_row() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let out_dir = std::env::temp_dir().join("mh_map_export_rows");
+        export_rows(&map, &out_dir).unwrap();
+
+        let row = image::open(out_dir.join("row_0.png")).unwrap().to_rgba8();
+        assert_eq!(row, render(&map).unwrap());
+        assert!(!out_dir.join("row_1.png").exists());
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+
+    #[test]
+    fn export_deepzoom_writes_every_level() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let out_dir = std::env::temp_dir().join("mh_map_export_deepzoom");
+        export_deepzoom(&map, &out_dir, 256).unwrap();
+
+        // 320x240 一直减半到 1x1 需要 9 次，共 10 层
+        let files_dir = out_dir.join("map_files");
+        assert!(files_dir.join("9").is_dir());
+        assert!(!files_dir.join("10").exists());
+
+        // 最高层 320 宽切成两列，右边的瓦片只剩 64 宽
+        let size = |level: &str, name: &str| {
+            let tile = image::open(files_dir.join(level).join(name)).unwrap();
+            (tile.width(), tile.height())
+        };
+        assert_eq!(size("9", "0_0.jpg"), (256, 240));
+        assert_eq!(size("9", "1_0.jpg"), (64, 240));
+        assert!(!files_dir.join("9").join("0_1.jpg").exists());
+        assert_eq!(size("8", "0_0.jpg"), (160, 120));
+        assert_eq!(size("0", "0_0.jpg"), (1, 1));
+
+        let descriptor = fs::read_to_string(out_dir.join("map.dzi")).unwrap();
+        assert!(descriptor.contains("<Size Width=\"320\" Height=\"240\"/>"));
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+
+    #[test]
+    fn export_tiles_round_trip() {
+        let mut builder = crate::builder::MapBuilder::with_dimensions(640, 480);
+        builder.set_tile(0, 0, fixture::tile_jpeg(320, 240));
+        builder.set_tile(1, 1, fixture::tile_jpeg(320, 240));
+        let map = builder.build().unwrap();
+        let expected = map.units[0].load_image().unwrap().to_rgba8();
+
+        let formats = [
+            TileFormat::Png,
+            TileFormat::Jpeg(95),
+            #[cfg(feature = "webp")]
+            TileFormat::WebP,
+        ];
+        for format in formats {
+            let name = format!("mh_map_export_{}", format.extension());
+            let out_dir = std::env::temp_dir().join(name);
+            export_tiles(&map, &out_dir, format).unwrap();
+
+            let path = |row, col| out_dir.join(format!("{}_{}.{}", row, col, format.extension()));
+            assert!(!path(0, 1).exists());
+            assert!(!path(1, 0).exists());
+            for (row, col) in [(0, 0), (1, 1)] {
+                let tile = image::open(path(row, col)).unwrap().to_rgba8();
+                assert_eq!(tile.dimensions(), (TILE_WIDTH, TILE_HEIGHT));
+                if let TileFormat::Jpeg(_) = format {
+                    // 有损压缩，只比较颜色是否接近
+                    let (got, want) = (tile.get_pixel(160, 120), expected.get_pixel(160, 120));
+                    assert!(got.0.iter().zip(want.0).all(|(a, b)| a.abs_diff(b) <= 8));
+                } else {
+                    assert_eq!(tile, expected);
+                }
+            }
+            fs::remove_dir_all(&out_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn export_tiles_pads_edge_tiles() {
+        let mut builder = crate::builder::MapBuilder::with_dimensions(400, 240);
+        builder.set_tile(0, 1, fixture::tile_jpeg(80, 240));
+        let map = builder.build().unwrap();
+        let out_dir = std::env::temp_dir().join("mh_map_export_padded");
+
+        let mut options = TileExportOptions::new(TileFormat::Png);
+        options.pad_edges = true;
+        options.pad_color = Rgba([0, 255, 0, 255]);
+        let clips = export_tiles_with_options(&map, &out_dir, &options).unwrap();
+        assert_eq!(clips, vec![(1, (320, 0, 80, 240))]);
+
+        let tile = image::open(out_dir.join("0_1.png")).unwrap().to_rgba8();
+        assert_eq!(tile.dimensions(), (TILE_WIDTH, TILE_HEIGHT));
+        assert_eq!(*tile.get_pixel(100, 10), Rgba([0, 255, 0, 255]));
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+
+    #[test]
+    fn masks_to_svg_clips_out_of_bounds_masks() {
+        let mut map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let full = vec![0xFF; 2];
+        map.masks = vec![
+            // 一半在地图右边外面
+            Mask::new(0, fixture::WIDTH - 4, 0, 8, 1, MaskKind::Occlusion, full.clone()).unwrap(),
+            // 坐标大到相加会溢出
+            Mask::new(1, u32::MAX - 2, u32::MAX, 8, 1, MaskKind::Occlusion, full).unwrap(),
+        ];
+        let svg = masks_to_svg(&map);
+        assert_eq!(svg.matches("<rect").count(), 1);
+        assert!(svg.contains(&format!(
+            "<rect x=\"{}\" y=\"0\" width=\"4\" height=\"1\"/>",
+            fixture::WIDTH - 4
+        )));
+    }
+
+    #[test]
+    fn tmx_document_works() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let tmx = tmx_document(&map, "map_tileset.png");
+        let (x, y, width, height) = fixture::MASK;
+        assert!(tmx.contains("width=\"1\" height=\"1\" tilewidth=\"320\" tileheight=\"240\""));
+        assert!(tmx.contains("<image source=\"map_tileset.png\" width=\"320\" height=\"240\"/>"));
+        assert!(tmx.contains("<data encoding=\"csv\">\n1\n</data>"));
+        assert!(tmx.contains(&format!(
+            "<object id=\"1\" name=\"mask 0\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>",
+            x, y, width, height
+        )));
+    }
+}
@@ -0,0 +1,79 @@
+//! 测试用的地图文件，在内存中构造，不依赖真实的游戏文件
+//!
+//! 文件布局 (所有整数都是小端):
+//!
+//! | 偏移 | 内容 |
+//! | --- | --- |
+//! | 0 | "0.1M" 标识, 宽度, 高度 |
+//! | 12 | 单元索引表，`rows * cols` 个 u32 偏移 |
+//! | 16 | 遮罩表: 未知 u32, 遮罩数量, 每个遮罩的偏移 |
+//! | 28 | 遮罩: x, y, 宽, 高, 压缩后大小, LZO 压缩的 2 bit 格子数据 |
+//! | .. | 单元: 前缀数量 n, n 个 u32, "2GPJ", 大小, 完整的 JPEG |
+
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    ColorType, ImageEncoder, Rgb, RgbImage, Rgba, RgbaImage,
+};
+
+pub(crate) const WIDTH: u32 = 320;
+pub(crate) const HEIGHT: u32 = 240;
+
+/// 遮罩的位置和大小 (x, y, width, height)，所有格子的值都是 3
+pub(crate) const MASK: (u32, u32, u32, u32) = (10, 20, 8, 4);
+
+/// 一张纯色的 JPEG
+pub(crate) fn tile_jpeg(width: u32, height: u32) -> Vec<u8> {
+    let tile = RgbImage::from_pixel(width, height, Rgb([200, 100, 50]));
+    let mut jpeg = vec![];
+    let mut encoder = JpegEncoder::new_with_quality(&mut jpeg, 90);
+    encoder.encode(&tile, width, height, ColorType::Rgb8).unwrap();
+    jpeg
+}
+
+/// 一张半透明的 PNG
+pub(crate) fn tile_png(width: u32, height: u32) -> Vec<u8> {
+    let tile = RgbaImage::from_pixel(width, height, Rgba([50, 100, 200, 128]));
+    let mut png = vec![];
+    PngEncoder::new(&mut png)
+        .write_image(&tile, width, height, ColorType::Rgba8)
+        .unwrap();
+    png
+}
+
+/// LZO 压缩后的遮罩数据
+pub(crate) fn mask_payload() -> Vec<u8> {
+    let (_, _, width, height) = MASK;
+    // 宽度已经是 4 的倍数，每个字节 4 个格子
+    let raw = vec![0xFFu8; (width * height / 4) as usize];
+    crate::compress::lzo_compress(&raw).unwrap()
+}
+
+/// 完整的地图文件: 1 个 2GPJ 单元和 1 个遮罩
+pub(crate) fn map_bytes() -> Vec<u8> {
+    let jpeg = tile_jpeg(WIDTH, HEIGHT);
+    let mask = mask_payload();
+    let (x, y, width, height) = MASK;
+
+    let mask_offset = 28u32;
+    let unit_offset = mask_offset + 20 + mask.len() as u32;
+
+    let mut bytes = b"0.1M".to_vec();
+    bytes.extend_from_slice(&WIDTH.to_le_bytes());
+    bytes.extend_from_slice(&HEIGHT.to_le_bytes());
+    bytes.extend_from_slice(&unit_offset.to_le_bytes());
+
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&mask_offset.to_le_bytes());
+
+    for value in [x, y, width, height, mask.len() as u32] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.extend_from_slice(&mask);
+
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(b"2GPJ");
+    bytes.extend_from_slice(&(jpeg.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&jpeg);
+    bytes
+}
@@ -0,0 +1,214 @@
+//! GEPJ 单元的 JPEG 修复
+
+use crate::buffer_utils;
+
+/// JPEG 文件开头的 SOI 标记
+pub(crate) const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+/// JPEG 文件结尾的 EOI 标记
+pub(crate) const JPEG_EOI: [u8; 2] = [0xFF, 0xD9];
+
+/// fix_jpeg 修复时发现的重启标记信息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JpegFix {
+    /// 扫描头之前 DRI 段中的重启间隔，没有 DRI 段时为 None
+    pub restart_interval: Option<u16>,
+    /// 扫描数据中原样保留的重启标记 (FFD0 到 FFD7) 的个数
+    pub restart_markers: usize,
+}
+
+/// 把 GEPJ 单元的数据修复成标准的 JPEG，结果写入 out (会先清空)
+///
+/// out 可以在多个单元之间复用，避免每个单元都重新分配内存。
+/// 逻辑是参考 https://www.jianshu.com/p/7faf26c9648a 实现的:
+/// 按 FFDA 段中的长度字段补上缺少的 3 个字节并修正长度，之后每个 FF 后面补一个 00，直到 FFD9。
+/// 有 DRI 段时扫描数据中的 FFD0 到 FFD7 是重启标记，不补 00
+pub fn fix_jpeg(input: &[u8], out: &mut Vec<u8>) -> JpegFix {
+    fix_jpeg_with(input, out, true)
+}
+
+/// 和 fix_jpeg 一样，restart_markers 为 false 时忽略 DRI 段，每个 FF 后面都补 00
+pub fn fix_jpeg_with(input: &[u8], out: &mut Vec<u8>, restart_markers: bool) -> JpegFix {
+    let mut fix = JpegFix::default();
+    out.clear();
+    // 每个 FF 后面都可能多一个字节，先按 1/16 预留
+    out.reserve(input.len() + input.len() / 16 + 3);
+
+    let sos = match input.windows(2).position(|w| w == [0xFF, 0xDA]) {
+        Some(sos) => sos,
+        None => {
+            out.extend_from_slice(input);
+            return fix;
+        }
+    };
+    fix.restart_interval = restart_interval(&input[..sos]);
+    // 间隔为 0 表示不使用重启标记
+    let restarts = restart_markers && matches!(fix.restart_interval, Some(n) if n > 0);
+
+    // GEPJ 的 FFDA 段长度只算了长度字段后面的字节 (常见的 3 分量是 9 个)，
+    // 缺少的 Ss/Se/Ah Al 3 个字节要插在这些字节之后，长度也要跟着加 3
+    let length = match input
+        .get(sos + 2..sos + 4)
+        .map(|mut bytes| buffer_utils::read_u16_be(&mut bytes))
+    {
+        Some(Ok(length)) => length as usize,
+        _ => {
+            out.extend_from_slice(input);
+            return fix;
+        }
+    };
+    let header_end = (sos + 4 + length).min(input.len());
+    out.extend_from_slice(&input[..header_end]);
+    let fixed_length = (length as u16).wrapping_add(3).to_be_bytes();
+    out[sos + 2..sos + 4].copy_from_slice(&fixed_length);
+    out.extend_from_slice(&[0x00, 0x3F, 0x00]);
+
+    let mut index = header_end;
+    while index < input.len() {
+        let byte = input[index];
+        out.push(byte);
+        if byte == 0xFF {
+            match input.get(index + 1) {
+                Some(0xD9) => {
+                    out.extend_from_slice(&input[index + 1..]);
+                    return fix;
+                }
+                Some(marker @ 0xD0..=0xD7) if restarts => {
+                    out.push(*marker);
+                    fix.restart_markers += 1;
+                    index += 1;
+                }
+                _ => out.push(0x00),
+            }
+        }
+        index += 1;
+    }
+    fix
+}
+
+/// 按段查找 SOF 段，返回其中的宽高，不解码图片数据。数据不完整或者没有 SOF 段时返回 None
+pub(crate) fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(&JPEG_SOI) {
+        return None;
+    }
+    let mut index = 2;
+    while index + 4 <= data.len() {
+        if data[index] != 0xFF {
+            return None;
+        }
+        let marker = data[index + 1];
+        // 段之间可以有多余的 FF 填充
+        if marker == 0xFF {
+            index += 1;
+            continue;
+        }
+        // C4 (DHT)、C8 (JPG)、CC (DAC) 不是 SOF
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            // 长度、精度之后是高和宽
+            let sof = data.get(index + 5..index + 9)?;
+            let height = u16::from_be_bytes([sof[0], sof[1]]) as u32;
+            let width = u16::from_be_bytes([sof[2], sof[3]]) as u32;
+            return Some((width, height));
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let length = u16::from_be_bytes([data[index + 2], data[index + 3]]) as usize;
+        index += 2 + length;
+    }
+    None
+}
+
+/// 按段依次查找扫描头之前的 DRI (FFDD) 段，返回其中的重启间隔
+fn restart_interval(header: &[u8]) -> Option<u16> {
+    // 跳过开头的 FFD8
+    let mut index = 2;
+    while index + 4 <= header.len() {
+        if header[index] != 0xFF {
+            return None;
+        }
+        let length = u16::from_be_bytes([header[index + 2], header[index + 3]]) as usize;
+        if header[index + 1] == 0xDD {
+            return header
+                .get(index + 4..index + 6)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+        }
+        index += 2 + length;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fix_jpeg_works() {
+        let mut input = vec![0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x09];
+        input.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        input.extend_from_slice(&[0x12, 0xFF, 0x34, 0xFF, 0xD9]);
+
+        let mut out = vec![0xAA; 4];
+        fix_jpeg(&input, &mut out);
+
+        let mut expected = vec![0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x0C];
+        expected.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        expected.extend_from_slice(&[0x00, 0x3F, 0x00]);
+        expected.extend_from_slice(&[0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD9]);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn fix_jpeg_uses_scan_header_length() {
+        // 单分量的扫描头只有 7 个字节
+        let mut input = vec![0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x07];
+        input.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7]);
+        input.extend_from_slice(&[0xFF, 0xD9]);
+
+        let mut out = vec![];
+        fix_jpeg(&input, &mut out);
+
+        let mut expected = vec![0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x0A];
+        expected.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7]);
+        expected.extend_from_slice(&[0x00, 0x3F, 0x00, 0xFF, 0xD9]);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn fix_jpeg_keeps_restart_markers() {
+        // FFDD 段: 长度 4，重启间隔 2
+        let mut input = vec![0xFF, 0xD8, 0xFF, 0xDD, 0x00, 0x04, 0x00, 0x02];
+        input.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x01, 0x01]);
+        input.extend_from_slice(&[0x12, 0xFF, 0xD0, 0x34, 0xFF, 0xD1, 0xFF, 0xD9]);
+
+        let mut out = vec![];
+        let fix = fix_jpeg(&input, &mut out);
+        assert_eq!(fix.restart_interval, Some(2));
+        assert_eq!(fix.restart_markers, 2);
+
+        let mut expected = vec![0xFF, 0xD8, 0xFF, 0xDD, 0x00, 0x04, 0x00, 0x02];
+        expected.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x04, 0x01, 0x00, 0x3F, 0x00]);
+        expected.extend_from_slice(&[0x12, 0xFF, 0xD0, 0x34, 0xFF, 0xD1, 0xFF, 0xD9]);
+        assert_eq!(out, expected);
+
+        // 关掉之后和没有 DRI 段时一样处理
+        let fix = fix_jpeg_with(&input, &mut out, false);
+        assert_eq!(fix.restart_markers, 0);
+        assert_eq!(&out[out.len() - 6..], [0x34, 0xFF, 0x00, 0xD1, 0xFF, 0xD9]);
+    }
+
+    #[test]
+    fn jpeg_dimensions_reads_sof() {
+        let jpeg = crate::fixture::tile_jpeg(12, 7);
+        assert_eq!(jpeg_dimensions(&jpeg), Some((12, 7)));
+        assert_eq!(jpeg_dimensions(&jpeg[..20]), None);
+        assert_eq!(jpeg_dimensions(&[0xFF, 0xD8, 0xFF, 0xD9]), None);
+    }
+
+    #[test]
+    fn fix_jpeg_without_sos() {
+        let input = [0xFF, 0xD8, 0x01, 0xFF, 0xD9];
+        let mut out = vec![];
+        fix_jpeg(&input, &mut out);
+        assert_eq!(out, input);
+    }
+}
@@ -0,0 +1,68 @@
+use std::ops::Range;
+
+/// 文件中一段字节的用途
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// 文件头和单元索引表
+    Header,
+    /// 遮罩数量和遮罩偏移表
+    MaskTable,
+    /// 第 n 个遮罩
+    Mask(usize),
+    /// 索引表中第 n 个单元
+    Unit(usize),
+}
+
+/// 解析时读取过的字节区间
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    pub ranges: Vec<(Section, Range<u64>)>,
+}
+
+impl Layout {
+    pub fn push(&mut self, section: Section, range: Range<u64>) {
+        self.ranges.push((section, range));
+    }
+
+    /// 没有被任何区间覆盖的字节区间 (包括文件末尾多出来的字节)
+    pub fn gaps(&self, file_len: u64) -> Vec<Range<u64>> {
+        let mut ranges = self
+            .ranges
+            .iter()
+            .map(|(_, range)| range.clone())
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|range| range.start);
+
+        let mut gaps = vec![];
+        let mut covered = 0;
+        for range in ranges {
+            if range.start > covered {
+                gaps.push(covered..range.start.min(file_len));
+            }
+            covered = covered.max(range.end);
+            if covered >= file_len {
+                break;
+            }
+        }
+        if covered < file_len {
+            gaps.push(covered..file_len);
+        }
+        gaps.retain(|gap| !gap.is_empty());
+        gaps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_works() {
+        let mut layout = Layout::default();
+        layout.push(Section::Header, 0..10);
+        layout.push(Section::Unit(1), 20..30);
+        layout.push(Section::Unit(0), 12..22);
+        assert_eq!(layout.gaps(40), vec![10..12, 30..40]);
+        assert_eq!(layout.gaps(30), vec![10..12]);
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+
+use image::RgbaImage;
+
+use crate::decoder::{ImageDecoder, TileDecoder};
+use crate::mask::{decode_mask_cells, read_mask_data, read_mask_table, Mask, MaskGrid, MaskTable};
+use crate::new_map::{read_header, read_unit_at, DecodeOptions, MapHeader};
+use crate::parser::ParserContext;
+
+/// 只读取文件头和遮罩表，单元和遮罩在用到的时候才解码的地图，适合只显示一部分地图的查看器
+pub struct LazyMap {
+    pub map_header: MapHeader,
+    pub mask_table: MaskTable,
+    file: ParserContext<BufReader<File>>,
+    cache: TileCache,
+    decoder: Box<dyn TileDecoder + Send>,
+    /// 已经解压的遮罩，和 mask_table.offsets 一一对应
+    masks: Vec<Option<Mask>>,
+}
+
+impl LazyMap {
+    /// 打开地图文件，capacity 是最多缓存多少个解码后的单元
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> anyhow::Result<LazyMap> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut file = ParserContext::new(reader, DecodeOptions::default())?;
+        let map_header = read_header(&mut file)?;
+        let mask_table = read_mask_table(&mut file)?;
+        let masks = vec![None; mask_table.mask_count()];
+        Ok(LazyMap {
+            map_header,
+            mask_table,
+            file,
+            cache: TileCache::new(capacity),
+            decoder: Box::new(ImageDecoder),
+            masks,
+        })
+    }
+
+    /// 用指定的解码器解码单元，默认是 ImageDecoder。已经缓存的单元不会重新解码
+    pub fn with_decoder(mut self, decoder: impl TileDecoder + Send + 'static) -> LazyMap {
+        self.decoder = Box::new(decoder);
+        self
+    }
+
+    /// 第 index 个遮罩，第一次访问时才读取和解压，之后从缓存中取
+    pub fn mask(&mut self, index: usize) -> anyhow::Result<&Mask> {
+        let offset = *self
+            .mask_table
+            .offsets
+            .get(index)
+            .ok_or_else(|| anyhow::anyhow!("Mask {} out of range", index))?;
+        if self.masks[index].is_none() {
+            let mask = read_mask_data(&mut self.file, index, offset)?;
+            // 遮罩解压后一直保留，和 decode 一样限制总量
+            self.file.charge(mask.data.len() as u64)?;
+            self.masks[index] = Some(mask);
+        }
+        Ok(self.masks[index].as_ref().unwrap())
+    }
+
+    /// 按绘制顺序逐个解压遮罩并展开格子，每次 next 只处理一个遮罩，
+    /// 提前停止迭代时后面的遮罩不会被解压
+    pub fn masks(&mut self) -> LazyMasks<'_> {
+        LazyMasks {
+            map: self,
+            next: 0,
+        }
+    }
+
+    /// 获取第 row 行第 col 列的单元图片，优先从缓存中取
+    pub fn tile(&mut self, row: u32, col: u32) -> anyhow::Result<&RgbaImage> {
+        let offset = self
+            .map_header
+            .offset_of(row, col)
+            .ok_or_else(|| anyhow::anyhow!("Tile ({}, {}) out of range", row, col))?;
+        let slot = self.map_header.slot_of(row, col);
+
+        if self.cache.touch(slot) {
+            self.cache.hits += 1;
+        } else {
+            self.cache.misses += 1;
+            let unit = read_unit_at(&mut self.file, slot, offset)?
+                .ok_or_else(|| anyhow::anyhow!("Unknown unit flag at offset {}", offset))?;
+            let image = unit.decode_with(self.decoder.as_ref())?;
+            self.cache.insert(slot, image);
+        }
+        Ok(&self.cache.tiles[&slot])
+    }
+
+    /// 缓存命中次数
+    pub fn cache_hits(&self) -> u64 {
+        self.cache.hits
+    }
+
+    /// 缓存未命中次数
+    pub fn cache_misses(&self) -> u64 {
+        self.cache.misses
+    }
+}
+
+/// LazyMap::masks 返回的迭代器，遮罩会被复制一份返回，缓存中的仍然保留
+pub struct LazyMasks<'a> {
+    map: &'a mut LazyMap,
+    next: usize,
+}
+
+impl Iterator for LazyMasks<'_> {
+    type Item = anyhow::Result<(Mask, MaskGrid)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.map.mask_table.mask_count() {
+            return None;
+        }
+        let index = self.next;
+        self.next += 1;
+        Some(self.map.mask(index).and_then(|mask| {
+            let grid = decode_mask_cells(mask)?;
+            Ok((mask.clone(), grid))
+        }))
+    }
+}
+
+/// 按最近使用顺序淘汰的单元缓存
+struct TileCache {
+    capacity: usize,
+    tiles: HashMap<usize, RgbaImage>,
+    /// 最近使用的在最后面
+    order: VecDeque<usize>,
+    hits: u64,
+    misses: u64,
+}
+
+impl TileCache {
+    fn new(capacity: usize) -> TileCache {
+        TileCache {
+            // 至少要能放下刚解码的那一个
+            capacity: capacity.max(1),
+            tiles: HashMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// 标记为最近使用，不在缓存中时返回 false
+    fn touch(&mut self, slot: usize) -> bool {
+        if !self.tiles.contains_key(&slot) {
+            return false;
+        }
+        if let Some(position) = self.order.iter().position(|s| *s == slot) {
+            self.order.remove(position);
+        }
+        self.order.push_back(slot);
+        true
+    }
+
+    fn insert(&mut self, slot: usize, image: RgbaImage) {
+        while self.tiles.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.tiles.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        self.tiles.insert(slot, image);
+        self.order.push_back(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::mask::MaskCell;
+
+    #[test]
+    fn masks_are_decompressed_on_demand() {
+        let path = std::env::temp_dir().join("mh_map_lazy_masks.map");
+        std::fs::write(&path, fixture::map_bytes()).unwrap();
+        let mut map = LazyMap::open(&path, 1).unwrap();
+
+        assert_eq!(map.mask_table.mask_count(), 1);
+        assert!(map.masks[0].is_none());
+
+        let masks = map.masks().collect::<anyhow::Result<Vec<_>>>().unwrap();
+        let (x, y, width, height) = fixture::MASK;
+        let (mask, grid) = &masks[0];
+        assert_eq!((mask.x(), mask.y()), (x, y));
+        assert_eq!((grid.width, grid.height), (width, height));
+        assert!(grid.cells.iter().all(|cell| *cell == MaskCell::Full));
+        assert!(map.masks[0].is_some());
+        assert!(map.mask(1).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tile_uses_custom_decoder() {
+        struct Solid;
+        impl TileDecoder for Solid {
+            fn decode(&self, _bytes: &[u8]) -> anyhow::Result<RgbaImage> {
+                Ok(RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255])))
+            }
+        }
+
+        let path = std::env::temp_dir().join("mh_map_lazy_decoder.map");
+        std::fs::write(&path, fixture::map_bytes()).unwrap();
+        let mut map = LazyMap::open(&path, 1).unwrap().with_decoder(Solid);
+        assert_eq!(map.tile(0, 0).unwrap().dimensions(), (2, 2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tile_hits_and_evicts_from_cache() {
+        let mut builder = crate::builder::MapBuilder::with_dimensions(960, 240);
+        for col in 0..3 {
+            builder.set_tile(0, col, fixture::tile_jpeg(320, 240));
+        }
+        let bytes = crate::encode::encode(&builder.build().unwrap()).unwrap();
+        let path = std::env::temp_dir().join("mh_map_lazy_tiles.map");
+        std::fs::write(&path, bytes).unwrap();
+        let mut map = LazyMap::open(&path, 2).unwrap();
+
+        map.tile(0, 0).unwrap();
+        map.tile(0, 0).unwrap();
+        assert_eq!((map.cache_hits(), map.cache_misses()), (1, 1));
+
+        // 放满 2 个之后再读第 3 个，最久没用的 (0, 0) 被淘汰
+        map.tile(0, 1).unwrap();
+        map.tile(0, 2).unwrap();
+        assert_eq!((map.cache_hits(), map.cache_misses()), (1, 3));
+        map.tile(0, 2).unwrap();
+        map.tile(0, 0).unwrap();
+        assert_eq!((map.cache_hits(), map.cache_misses()), (2, 4));
+        assert!(map.tile(1, 0).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tile_cache_evicts_least_recently_used() {
+        let mut cache = TileCache::new(2);
+        cache.insert(0, RgbaImage::new(1, 1));
+        cache.insert(1, RgbaImage::new(1, 1));
+        assert!(cache.touch(0));
+        cache.insert(2, RgbaImage::new(1, 1));
+
+        assert!(cache.touch(0));
+        assert!(!cache.touch(1));
+        assert!(cache.touch(2));
+    }
+}
@@ -1,3 +1,25 @@
 mod buffer_utils;
+pub mod batch;
+pub mod builder;
+pub mod compress;
+pub mod crop;
+pub mod decoder;
+pub mod diff;
+pub mod encode;
+pub mod error;
+pub mod export;
+#[cfg(test)]
+mod fixture;
+pub mod jpeg;
+pub mod layout;
+pub mod lazy;
+pub mod mask;
+#[cfg(feature = "serde")]
+pub mod metadata;
 pub mod new_map;
-mod mask;
+mod parser;
+pub mod prelude;
+pub mod render;
+pub mod validate;
+
+pub use error::{MapError, Result};
@@ -1 +1,827 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Seek, Write},
+    path::Path,
+};
 
+use image::{Rgba, RgbaImage};
+
+use crate::buffer_utils;
+use crate::compress::{lzo_compress, lzo_decompress, max_decompressed_len};
+use crate::error::MapError;
+use crate::layout::Section;
+use crate::new_map::{DecodeOptions, Map, RawSpan};
+use crate::parser::ParserContext;
+use crate::validate::MAX_DIMENSION;
+
+/// 遮罩数据
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct Mask {
+    /// 在文件偏移表中的顺序，也是绘制顺序
+    pub(crate) index: usize,
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) size: u32,
+    pub(crate) kind: MaskKind,
+    /// 解压后的数据。Occlusion 每个格子占 2 bit，每一行按 4 个格子对齐；
+    /// Rgb565 每个像素 2 字节
+    pub(crate) data: Vec<u8>,
+    /// 开启 DecodeOptions::preserve_raw 时记录的原始字节，包括位置、大小和压缩的数据
+    pub(crate) raw: Option<RawSpan>,
+}
+
+/// 遮罩数据的格式，读取时按解压出来的大小区分
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaskKind {
+    /// 每个格子 2 bit，只表示是否遮挡，目前见过的文件都是这种
+    #[default]
+    Occlusion,
+    /// 每个像素一个小端的 RGB565 颜色，解压后正好是 `width * height * 2` 字节
+    Rgb565,
+}
+
+impl Mask {
+    /// 用解压后的数据构造遮罩，index 是在遮罩表中的位置。
+    /// data 的长度要和宽高、格式对得上 (见 Mask 的 data 字段)，否则返回 MaskSizeMismatch
+    pub fn new(
+        index: usize,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        kind: MaskKind,
+        data: Vec<u8>,
+    ) -> crate::Result<Mask> {
+        let mask = Mask {
+            index,
+            x,
+            y,
+            width,
+            height,
+            size: 0,
+            kind,
+            data,
+            raw: None,
+        };
+        check_mask_size(&mask, 0)?;
+        Ok(mask)
+    }
+
+    /// 在文件偏移表中的顺序，序号大的遮罩绘制在序号小的上面
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+
+    pub fn y(&self) -> u32 {
+        self.y
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// 遮罩数据的格式
+    pub fn kind(&self) -> MaskKind {
+        self.kind
+    }
+
+    /// 开启 DecodeOptions::preserve_raw 时记录的原始字节
+    pub fn raw(&self) -> Option<&RawSpan> {
+        self.raw.as_ref()
+    }
+
+    /// 以 Read 的方式读取解压后的遮罩数据
+    pub fn reader(&self) -> impl Read + '_ {
+        Cursor::new(self.data.as_slice())
+    }
+
+    /// 遮罩内第 (cx, cy) 个格子，直接从打包的数据中取，不用展开整个遮罩
+    fn cell(&self, cx: u32, cy: u32) -> Option<MaskCell> {
+        if cx >= self.width || cy >= self.height {
+            return None;
+        }
+        if self.kind == MaskKind::Rgb565 {
+            let index = (cy as usize * self.width as usize + cx as usize) * 2;
+            let color = self.data.get(index..index + 2)?;
+            return Some(rgb565_cell(u16::from_le_bytes([color[0], color[1]])));
+        }
+        let aligned = aligned_width(self.width).ok()? as usize;
+        let index = (cy as usize * aligned + cx as usize) << 1;
+        let byte = self.data.get(index >> 3)?;
+        Some(MaskCell::from_bits(byte >> (index % 8)))
+    }
+
+    /// 遮罩整个在 width x height 的地图范围内
+    pub fn fits_in(&self, width: u32, height: u32) -> bool {
+        let right = self.x.checked_add(self.width);
+        let bottom = self.y.checked_add(self.height);
+        matches!((right, bottom), (Some(r), Some(b)) if r <= width && b <= height)
+    }
+
+    /// 地图坐标 (px, py) 在遮罩范围内，并且对应的格子是遮挡的 (Full)
+    pub fn contains(&self, px: u32, py: u32) -> bool {
+        match (px.checked_sub(self.x), py.checked_sub(self.y)) {
+            (Some(cx), Some(cy)) => self.cell(cx, cy) == Some(MaskCell::Full),
+            _ => false,
+        }
+    }
+
+    /// 地图上的矩形 (x, y, w, h) 中至少有一个格子被这个遮罩遮挡
+    pub fn intersects(&self, x: u32, y: u32, w: u32, h: u32) -> bool {
+        let left = x.max(self.x);
+        let top = y.max(self.y);
+        let right = x.saturating_add(w).min(self.x.saturating_add(self.width));
+        let bottom = y.saturating_add(h).min(self.y.saturating_add(self.height));
+        (top..bottom).any(|py| (left..right).any(|px| self.contains(px, py)))
+    }
+
+    /// 只包含这个遮罩的 width x height 图片，不合成到地图上，方便单独查看
+    pub fn to_image(&self) -> anyhow::Result<RgbaImage> {
+        decode_mask_image(self)
+    }
+
+    /// 和 to_image 一样大小，但是用 color 着色，格子的取值 0-3 对应 color 透明度的 0 到 3/3
+    pub fn to_image_tinted(&self, color: Rgba<u8>) -> anyhow::Result<RgbaImage> {
+        let bits = decode_mask_bits(self)?;
+        let mut image = RgbaImage::new(self.width, self.height);
+        for (pixel, bit) in image.pixels_mut().zip(bits) {
+            let alpha = (color[3] as u32 * bit as u32 / 3) as u8;
+            *pixel = Rgba([color[0], color[1], color[2], alpha]);
+        }
+        Ok(image)
+    }
+}
+
+/// 每一行按 4 个格子对齐后的宽度
+fn aligned_width(width: u32) -> crate::Result<u32> {
+    let words = (width >> 2) + if width % 4 != 0 { 1 } else { 0 };
+    buffer_utils::checked_mul(words, 4, "mask aligned width")
+}
+
+/// 解压后的遮罩数据应有的字节数，每个格子 2 bit
+fn packed_len(width: u32, height: u32) -> crate::Result<usize> {
+    let cells = buffer_utils::checked_mul(aligned_width(width)?, height, "mask size")?;
+    Ok((cells >> 2) as usize)
+}
+
+/// 遮罩的宽高来自文件，超过 MAX_DIMENSION 的按损坏处理，不能按它分配内存
+fn check_mask_dimensions(width: u32, height: u32, offset: u32) -> crate::Result<()> {
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(MapError::MaskTooLarge {
+            width,
+            height,
+            offset,
+        });
+    }
+    Ok(())
+}
+
+/// RGB565 遮罩解压后应有的字节数
+fn rgb565_len(width: u32, height: u32) -> crate::Result<usize> {
+    let pixels = buffer_utils::checked_mul(width, height, "mask size")?;
+    Ok(buffer_utils::checked_mul(pixels, 2, "mask size")? as usize)
+}
+
+/// 彩色遮罩没有单独的遮挡信息，不是纯黑 (0) 的像素都当作遮挡
+fn rgb565_cell(color: u16) -> MaskCell {
+    if color != 0 {
+        MaskCell::Full
+    } else {
+        MaskCell::Empty
+    }
+}
+
+/// 把 RGB565 颜色展开成 8 位的 RGBA，纯黑当作透明
+fn rgb565_to_rgba(color: u16) -> Rgba<u8> {
+    let r = ((color >> 11) & 0x1F) as u32;
+    let g = ((color >> 5) & 0x3F) as u32;
+    let b = (color & 0x1F) as u32;
+    let alpha = if color != 0 { 0xFF } else { 0 };
+    Rgba([
+        ((r * 255 + 15) / 31) as u8,
+        ((g * 255 + 31) / 63) as u8,
+        ((b * 255 + 15) / 31) as u8,
+        alpha,
+    ])
+}
+
+/// 遮罩偏移表，只包含遮罩的位置，不包含遮罩数据
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct MaskTable {
+    /// 遮罩表开头的 u32，含义还不清楚 (可能是版本、层数或标志位)，原样保留方便对比研究
+    pub mask_table_flag: u32,
+    /// 每个遮罩在文件中的偏移，顺序即绘制顺序
+    pub offsets: Vec<u32>,
+}
+
+impl MaskTable {
+    pub fn mask_count(&self) -> usize {
+        self.offsets.len()
+    }
+}
+
+/// 读取遮罩偏移表，紧跟在文件头的单元索引表后面
+pub(crate) fn read_mask_table<R: Read + Seek>(
+    file: &mut ParserContext<R>,
+) -> anyhow::Result<MaskTable> {
+    let mask_table_flag = buffer_utils::read_u32(file)?;
+    let mask_num = buffer_utils::read_u32(file)?;
+    let table_len = buffer_utils::checked_mul(mask_num, 4, "mask table length")?;
+    if table_len as u64 > file.remaining()? {
+        return Err(anyhow::anyhow!(
+            "Mask table of {} entries exceeds the file",
+            mask_num
+        ));
+    }
+    let mask_data = buffer_utils::read_bytes(file, table_len as usize)?;
+    let offsets = mask_data
+        .chunks(4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect::<Vec<u32>>();
+
+    Ok(MaskTable {
+        mask_table_flag,
+        offsets,
+    })
+}
+
+/// 读取并解压偏移处的遮罩，index 是它在偏移表中的顺序
+pub fn read_mask_at<R: Read + Seek>(
+    file: &mut R,
+    index: usize,
+    offset: u32,
+) -> anyhow::Result<Mask> {
+    read_mask_data(&mut ParserContext::new(file, DecodeOptions::default())?, index, offset)
+}
+
+pub(crate) fn read_mask_data<R: Read + Seek>(
+    file: &mut ParserContext<R>,
+    index: usize,
+    offset: u32,
+) -> anyhow::Result<Mask> {
+    file.seek_to(offset as u64, "Mask")?;
+
+    let x = buffer_utils::read_u32(file)?;
+    let y = buffer_utils::read_u32(file)?;
+    let width = buffer_utils::read_u32(file)?;
+    let height = buffer_utils::read_u32(file)?;
+    let size = buffer_utils::read_u32(file)?;
+    if size as u64 > file.remaining()? {
+        return Err(anyhow::anyhow!(
+            "Mask of {} bytes at offset {} exceeds the file",
+            size,
+            offset
+        ));
+    }
+    check_mask_dimensions(width, height, offset)?;
+    let data = buffer_utils::read_bytes(file, (size) as usize)?;
+
+    // 先按 2 bit 的格式解压，放不下的话再按 RGB565 的大小试一次，
+    // 正好解压出 width * height * 2 字节的才当作彩色遮罩。
+    // 压缩数据不可能解压出这么多字节时不试，免得按文件中的宽高分配大块内存
+    let (kind, data) = match lzo_decompress(&data, packed_len(width, height)?) {
+        Ok(decompressed) => (MaskKind::Occlusion, decompressed),
+        Err(e) => {
+            let colored = rgb565_len(width, height)?;
+            if colored > max_decompressed_len(data.len()) {
+                return Err(e);
+            }
+            match lzo_decompress(&data, colored) {
+                Ok(decompressed) if decompressed.len() == colored => {
+                    (MaskKind::Rgb565, decompressed)
+                }
+                _ => return Err(e),
+            }
+        }
+    };
+
+    let raw = if file.options.preserve_raw {
+        Some(file.raw_span(offset as u64)?)
+    } else {
+        None
+    };
+
+    Ok(Mask {
+        index,
+        x,
+        y,
+        width,
+        height,
+        size,
+        kind,
+        data,
+        raw,
+    })
+}
+
+/// 检查解压出来的字节数是否和宽高对得上，不够的话遮罩只能画出一部分
+pub(crate) fn check_mask_size(mask: &Mask, offset: u32) -> crate::Result<()> {
+    let expected = match mask.kind {
+        MaskKind::Occlusion => packed_len(mask.width, mask.height)?,
+        MaskKind::Rgb565 => rgb565_len(mask.width, mask.height)?,
+    };
+    if mask.data.len() != expected {
+        return Err(MapError::MaskSizeMismatch {
+            expected,
+            got: mask.data.len(),
+            offset,
+        });
+    }
+    Ok(())
+}
+
+/// 把解压后的遮罩数据重新用 LZO 压缩，编码地图时使用
+pub(crate) fn compress_mask(mask: &Mask) -> anyhow::Result<Vec<u8>> {
+    lzo_compress(&mask.data)
+}
+
+/// 读取遮罩数据 (遮罩的图片是被压缩的，需要解压)
+/// 这个方法应该是有问题的
+///
+/// 解压出来的大小不对时 strict 模式下返回 MapError::MaskSizeMismatch，否则记录警告
+pub(crate) fn read_mask<R: Read + Seek>(
+    file: &mut ParserContext<R>,
+) -> anyhow::Result<(u32, Vec<Mask>)> {
+    let table_start = file.position()?;
+    let table = read_mask_table(file)?;
+    let table_end = file.position()?;
+    file.layout.push(Section::MaskTable, table_start..table_end);
+
+    let mut masks = Vec::new();
+    for (index, offset) in table.offsets.into_iter().enumerate() {
+        let mask = read_mask_data(file, index, offset)?;
+        file.charge(mask.data.len() as u64)?;
+        if let Err(e) = check_mask_size(&mask, offset) {
+            file.warn_or_fail(e)?;
+        }
+        let mask_end = file.position()?;
+        file.layout.push(Section::Mask(index), offset as u64..mask_end);
+        masks.push(mask);
+    }
+
+    Ok((table.mask_table_flag, masks))
+}
+
+/// 遮罩缓存文件的标识，最后一个字节是格式版本，格式变化时要改这个值
+const MASK_CACHE_MAGIC: [u8; 4] = *b"MHM\x02";
+
+/// 把已经解压的遮罩写到缓存文件，之后可以用 load_masks 读回来，不用再做 LZO 解压
+///
+/// 格式 (小端): 标识, 遮罩数量, 然后每个遮罩是
+/// 序号, x, y, 宽, 高, 压缩大小, 格式 (0 是 Occlusion, 1 是 Rgb565), 数据长度, 解压后的数据
+pub fn dump_masks<P: AsRef<Path>>(map: &Map, path: P) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&MASK_CACHE_MAGIC)?;
+    buffer_utils::write_u32(&mut writer, map.masks.len() as u32)?;
+    for mask in map.masks.iter() {
+        for value in [
+            mask.index as u32,
+            mask.x,
+            mask.y,
+            mask.width,
+            mask.height,
+            mask.size,
+            mask.kind as u32,
+            mask.data.len() as u32,
+        ] {
+            buffer_utils::write_u32(&mut writer, value)?;
+        }
+        writer.write_all(&mask.data)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// 读取 dump_masks 写的缓存文件，标识或版本不对时返回错误
+pub fn load_masks<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Mask>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MASK_CACHE_MAGIC {
+        return Err(anyhow::anyhow!("Not a mask cache file or unsupported version"));
+    }
+
+    let count = buffer_utils::read_u32(&mut reader)?;
+    let mut masks = vec![];
+    for _ in 0..count {
+        let index = buffer_utils::read_u32(&mut reader)? as usize;
+        let x = buffer_utils::read_u32(&mut reader)?;
+        let y = buffer_utils::read_u32(&mut reader)?;
+        let width = buffer_utils::read_u32(&mut reader)?;
+        let height = buffer_utils::read_u32(&mut reader)?;
+        let size = buffer_utils::read_u32(&mut reader)?;
+        let kind = match buffer_utils::read_u32(&mut reader)? {
+            0 => MaskKind::Occlusion,
+            1 => MaskKind::Rgb565,
+            other => return Err(anyhow::anyhow!("Unknown mask kind {} in cache file", other)),
+        };
+        let len = buffer_utils::read_u32(&mut reader)?;
+        // 缓存文件也可能损坏，数据长度不能超过按宽高算出来的大小
+        check_mask_dimensions(width, height, 0)?;
+        let expected = match kind {
+            MaskKind::Occlusion => packed_len(width, height)?,
+            MaskKind::Rgb565 => rgb565_len(width, height)?,
+        };
+        if len as usize > expected {
+            return Err(anyhow::anyhow!(
+                "Mask {} in cache file has {} bytes, at most {} expected",
+                index,
+                len,
+                expected
+            ));
+        }
+        // 不按 len 预先分配，文件中实际没有这么多字节时不会白白分配
+        let mut data = vec![];
+        (&mut reader).take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len as usize {
+            return Err(anyhow::anyhow!("Mask {} in cache file is truncated", index));
+        }
+        masks.push(Mask {
+            index,
+            x,
+            y,
+            width,
+            height,
+            size,
+            kind,
+            data,
+            raw: None,
+        });
+    }
+    Ok(masks)
+}
+
+/// 把遮罩解压后的数据展开成 `width * height` 个格子，每个格子一个字节，取值 0-3
+///
+/// 只做位运算，不分配图片，碰撞检测之类只关心遮挡的场景用这个就够了。
+/// 彩色遮罩不是纯黑的像素为 3，其余为 0
+pub fn decode_mask_bits(mask: &Mask) -> anyhow::Result<Vec<u8>> {
+    if mask.kind == MaskKind::Rgb565 {
+        let expected = rgb565_len(mask.width, mask.height)?;
+        if mask.data.len() < expected {
+            return Err(anyhow::anyhow!(
+                "Mask data too short: expected {} bytes, got {}",
+                expected,
+                mask.data.len()
+            ));
+        }
+        let bits = mask.data[..expected]
+            .chunks(2)
+            .map(|color| rgb565_cell(u16::from_le_bytes([color[0], color[1]])) as u8)
+            .collect();
+        return Ok(bits);
+    }
+
+    let aiginw = aligned_width(mask.width)? as usize;
+    let expected = packed_len(mask.width, mask.height)?;
+    if mask.data.len() < expected {
+        return Err(anyhow::anyhow!(
+            "Mask data too short: expected {} bytes, got {}",
+            expected,
+            mask.data.len()
+        ));
+    }
+
+    let mut bits = Vec::with_capacity(mask.width as usize * mask.height as usize);
+    for k in 0..mask.height as usize {
+        for i in 0..mask.width as usize {
+            let index = (k * aiginw + i) << 1;
+            let byte = mask.data[index >> 3];
+            bits.push((byte >> (index % 8)) & 3);
+        }
+    }
+    Ok(bits)
+}
+
+/// decode_mask_bits 的逆操作，把每格一个字节的数据打包成每格 2 bit、每行按 4 格对齐的数据
+pub(crate) fn pack_mask_bits(width: u32, height: u32, bits: &[u8]) -> anyhow::Result<Vec<u8>> {
+    check_mask_dimensions(width, height, 0)?;
+    let aiginw = aligned_width(width)? as usize;
+    let mut data = vec![0u8; packed_len(width, height)?];
+    for k in 0..height as usize {
+        for i in 0..width as usize {
+            let bit = bits.get(k * width as usize + i).copied().unwrap_or(0) & 3;
+            let index = (k * aiginw + i) << 1;
+            data[index >> 3] |= bit << (index % 8);
+        }
+    }
+    Ok(data)
+}
+
+/// 遮罩格子的状态，对应 2 bit 的取值
+///
+/// 目前只确定 Full (3) 表示遮挡，1 和 2 在已知的文件里很少出现，含义还不确定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskCell {
+    Empty = 0,
+    Partial = 1,
+    Reserved = 2,
+    Full = 3,
+}
+
+impl MaskCell {
+    /// 只取最低 2 bit
+    pub fn from_bits(bits: u8) -> MaskCell {
+        match bits & 3 {
+            0 => MaskCell::Empty,
+            1 => MaskCell::Partial,
+            2 => MaskCell::Reserved,
+            _ => MaskCell::Full,
+        }
+    }
+}
+
+/// 解码后的遮罩格子，行优先
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaskGrid {
+    pub width: u32,
+    pub height: u32,
+    pub cells: Vec<MaskCell>,
+}
+
+impl MaskGrid {
+    pub fn get(&self, x: u32, y: u32) -> Option<MaskCell> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.cells.get((y * self.width + x) as usize).copied()
+    }
+
+    /// 可视化，和 mask_bits_to_rgba 的效果一样
+    pub fn to_rgba(&self) -> RgbaImage {
+        let bits = self.cells.iter().map(|cell| *cell as u8).collect::<Vec<u8>>();
+        mask_bits_to_rgba(self.width, self.height, &bits)
+    }
+}
+
+/// 把遮罩解码成带类型的格子
+pub fn decode_mask_cells(mask: &Mask) -> anyhow::Result<MaskGrid> {
+    let cells = decode_mask_bits(mask)?
+        .into_iter()
+        .map(MaskCell::from_bits)
+        .collect();
+    Ok(MaskGrid {
+        width: mask.width,
+        height: mask.height,
+        cells,
+    })
+}
+
+impl Map {
+    /// 逐个展开遮罩的格子，每次 next 只展开一个，只需要前面几个遮罩时不用全部展开。
+    /// Map 中的遮罩在解码时已经解压，需要按需解压的话用 LazyMap::masks
+    pub fn mask_grids(&self) -> impl Iterator<Item = anyhow::Result<(&Mask, MaskGrid)>> + '_ {
+        self.masks
+            .iter()
+            .map(|mask| Ok((mask, decode_mask_cells(mask)?)))
+    }
+}
+
+/// 把 2 bit 的格子数据转成可视化的图片，取值为 3 的格子画成半透明黑色，其余透明
+pub fn mask_bits_to_rgba(width: u32, height: u32, bits: &[u8]) -> RgbaImage {
+    let mut image = RgbaImage::new(width, height);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let index = (y * width + x) as usize;
+        if bits.get(index) == Some(&3) {
+            *pixel = Rgba([0, 0, 0, 0xF0]);
+        }
+    }
+    image
+}
+
+/// 解码遮罩并生成可视化图片，在 decode_mask_bits 的基础上多了一次 RGBA 图片的分配和填充
+///
+/// 彩色遮罩画的是它自己的颜色
+pub fn decode_mask_image(mask: &Mask) -> anyhow::Result<RgbaImage> {
+    if mask.kind == MaskKind::Rgb565 {
+        let expected = rgb565_len(mask.width, mask.height)?;
+        let colors = mask
+            .data
+            .get(..expected)
+            .ok_or_else(|| anyhow::anyhow!("Mask data too short: expected {} bytes", expected))?;
+        let mut image = RgbaImage::new(mask.width, mask.height);
+        for (pixel, color) in image.pixels_mut().zip(colors.chunks(2)) {
+            *pixel = rgb565_to_rgba(u16::from_le_bytes([color[0], color[1]]));
+        }
+        return Ok(image);
+    }
+    let bits = decode_mask_bits(mask)?;
+    Ok(mask_bits_to_rgba(mask.width, mask.height, &bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_bytes(width: u32, height: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for value in [0, 0, width, height, data.len() as u32] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn read_mask_limits_aliased_entries() {
+        // 2048x2048 的遮罩解压后 1 MB，压缩后只有几 KB
+        let data = lzo_compress(&vec![0xFF; 2048 * 2048 / 4]).unwrap();
+        let table = |count: u32| {
+            let mut bytes = vec![];
+            let offset = 8 + 4 * count;
+            bytes.extend_from_slice(&0u32.to_le_bytes());
+            bytes.extend_from_slice(&count.to_le_bytes());
+            for _ in 0..count {
+                bytes.extend_from_slice(&offset.to_le_bytes());
+            }
+            bytes.extend_from_slice(&mask_bytes(2048, 2048, &data));
+            bytes
+        };
+
+        let options = DecodeOptions::default();
+        let context = |count| ParserContext::new(Cursor::new(table(count)), options.clone());
+        assert_eq!(read_mask(&mut context(1).unwrap()).unwrap().1.len(), 1);
+
+        // 同一个遮罩被引用很多次，每次都会再解压一份
+        let err = read_mask(&mut context(64).unwrap()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<MapError>(), Some(MapError::AllocationLimit { .. })));
+    }
+
+    #[test]
+    fn read_mask_rejects_huge_dimensions() {
+        let bytes = mask_bytes(u32::MAX, 2, &[0x11, 0, 0]);
+        let mut context = ParserContext::new(Cursor::new(bytes), DecodeOptions::default()).unwrap();
+        let err = read_mask_data(&mut context, 0, 0).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<MapError>(),
+            Some(MapError::MaskTooLarge { width: u32::MAX, height: 2, offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn read_mask_skips_impossible_rgb565_fallback() {
+        // 3 字节的压缩数据不可能解压出 60000x60000 的彩色遮罩，直接返回 2 bit 格式的错误
+        let bytes = mask_bytes(60000, 60000, &[0x11, 0, 0]);
+        let mut context = ParserContext::new(Cursor::new(bytes), DecodeOptions::default()).unwrap();
+        let err = read_mask_data(&mut context, 0, 0).err().unwrap();
+        assert!(matches!(err.downcast_ref::<MapError>(), Some(MapError::Decompress(_))));
+    }
+
+    #[test]
+    fn read_mask_table_rejects_overflowing_count() {
+        let mut bytes = vec![0u8; 4];
+        bytes.extend_from_slice(&0x4000_0000u32.to_le_bytes());
+        let mut context = ParserContext::new(Cursor::new(bytes), DecodeOptions::default()).unwrap();
+        let err = read_mask_table(&mut context).err().unwrap();
+        assert!(matches!(err.downcast_ref::<MapError>(), Some(MapError::Overflow(_))));
+    }
+
+    #[test]
+    fn decode_mask_bits_works() {
+        // 宽度 3 会按 4 对齐，每行 1 个字节
+        let mask = Mask {
+            index: 0,
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 2,
+            size: 0,
+            kind: MaskKind::Occlusion,
+            data: vec![0b0010_0111, 0b0000_1100],
+            raw: None,
+        };
+        let bits = decode_mask_bits(&mask).unwrap();
+        assert_eq!(bits, vec![3, 1, 2, 0, 3, 0]);
+
+        let image = mask.to_image().unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 0xF0]));
+        assert_eq!(image.get_pixel(1, 0), &Rgba([0, 0, 0, 0]));
+
+        assert!(mask.contains(0, 0));
+        assert!(!mask.contains(1, 0));
+        assert!(!mask.contains(0, 1));
+        assert!(mask.contains(1, 1));
+        assert!(!mask.contains(3, 0));
+        assert!(mask.intersects(0, 1, 2, 1));
+        assert!(!mask.intersects(1, 0, 2, 1));
+        assert!(!mask.intersects(10, 10, 5, 5));
+
+        let tinted = mask.to_image_tinted(Rgba([255, 0, 0, 255])).unwrap();
+        assert_eq!(tinted.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(tinted.get_pixel(1, 0), &Rgba([255, 0, 0, 85]));
+        assert_eq!(tinted.get_pixel(2, 0), &Rgba([255, 0, 0, 170]));
+        assert_eq!(tinted.get_pixel(0, 1)[3], 0);
+        assert_eq!(tinted.get_pixel(1, 1)[3], 255);
+    }
+
+    #[test]
+    fn read_rgb565_mask() {
+        // 2x2 的彩色遮罩: 红, 黑, 绿, 蓝
+        let colors = [0xF800u16, 0x0000, 0x07E0, 0x001F];
+        let raw = colors.iter().flat_map(|c| c.to_le_bytes()).collect::<Vec<u8>>();
+        let compressed = lzo_compress(&raw).unwrap();
+        let mut bytes = vec![];
+        for value in [5u32, 6, 2, 2, compressed.len() as u32] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&compressed);
+
+        let mask = read_mask_at(&mut Cursor::new(bytes), 0, 0).unwrap();
+        assert_eq!(mask.kind(), MaskKind::Rgb565);
+        assert!(check_mask_size(&mask, 0).is_ok());
+        assert_eq!(decode_mask_bits(&mask).unwrap(), vec![3, 0, 3, 3]);
+        assert!(mask.contains(5, 6));
+        assert!(!mask.contains(6, 6));
+
+        let image = mask.to_image().unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(1, 0)[3], 0);
+        assert_eq!(image.get_pixel(0, 1), &Rgba([0, 255, 0, 255]));
+        assert_eq!(image.get_pixel(1, 1), &Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn fits_in_checks_map_bounds() {
+        let mut mask = Mask {
+            index: 0,
+            x: 10,
+            y: 20,
+            width: 8,
+            height: 4,
+            size: 0,
+            kind: MaskKind::Occlusion,
+            data: vec![0; 8],
+            raw: None,
+        };
+        assert!(mask.fits_in(18, 24));
+        assert!(!mask.fits_in(17, 24));
+        mask.x = u32::MAX;
+        assert!(!mask.fits_in(u32::MAX, u32::MAX));
+    }
+
+    #[test]
+    fn check_mask_size_reports_mismatch() {
+        let mask = Mask {
+            index: 0,
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 4,
+            size: 0,
+            kind: MaskKind::Occlusion,
+            data: vec![0; 5],
+            raw: None,
+        };
+        assert_eq!(
+            check_mask_size(&mask, 28),
+            Err(MapError::MaskSizeMismatch {
+                expected: 8,
+                got: 5,
+                offset: 28
+            })
+        );
+    }
+
+    #[test]
+    fn dump_and_load_masks() {
+        let map = crate::new_map::decode_from_bytes(&crate::fixture::map_bytes()).unwrap();
+        let path = std::env::temp_dir().join("mh_map_masks.bin");
+        dump_masks(&map, &path).unwrap();
+        let masks = load_masks(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(masks.len(), 1);
+        assert_eq!(masks[0].data, map.masks[0].data);
+        assert_eq!(
+            (masks[0].x, masks[0].y, masks[0].width, masks[0].height),
+            (map.masks[0].x, map.masks[0].y, map.masks[0].width, map.masks[0].height)
+        );
+    }
+
+    #[test]
+    fn load_masks_rejects_oversized_data() {
+        let map = crate::new_map::decode_from_bytes(&crate::fixture::map_bytes()).unwrap();
+        let path = std::env::temp_dir().join("mh_map_masks_corrupt.bin");
+        dump_masks(&map, &path).unwrap();
+        // 标识、数量和 7 个字段之后是数据长度
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(load_masks(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
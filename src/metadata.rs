@@ -0,0 +1,96 @@
+//! 地图信息的 JSON 输出，只包含字段，不包含单元和遮罩的数据
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::new_map::Map;
+
+/// to_metadata_json 输出的地图信息
+#[derive(Serialize)]
+struct MapMetadata<'a> {
+    width: u32,
+    height: u32,
+    rows: u32,
+    cols: u32,
+    /// 每种类型标识的单元个数
+    unit_flags: BTreeMap<&'a str, usize>,
+    mask_count: usize,
+    units: Vec<UnitMetadata<'a>>,
+}
+
+#[derive(Serialize)]
+struct UnitMetadata<'a> {
+    slot: usize,
+    row: u32,
+    col: u32,
+    flag: &'a str,
+    /// 单元在文件中的偏移
+    offset: u32,
+    /// 文件中记录的大小
+    size: u32,
+    /// 修复之后的数据大小
+    data_len: usize,
+    valid: bool,
+}
+
+impl Map {
+    /// 把地图的宽高、单元网格、各类型单元个数、遮罩个数和每个单元的偏移大小输出成一行 JSON，
+    /// 不包含任何图片数据，可以直接作为 NDJSON 的一条记录写入搜索索引
+    pub fn to_metadata_json(&self) -> String {
+        let header = &self.map_header;
+        let mut unit_flags = BTreeMap::new();
+        for unit in self.units.iter() {
+            *unit_flags.entry(unit.unit_flag.as_str()).or_insert(0) += 1;
+        }
+        let units = self
+            .units
+            .iter()
+            .map(|unit| {
+                let (row, col) = header.position_of(unit.slot);
+                UnitMetadata {
+                    slot: unit.slot,
+                    row,
+                    col,
+                    flag: &unit.unit_flag,
+                    offset: header.map_index_list.get(unit.slot).copied().unwrap_or(0),
+                    size: unit.size,
+                    data_len: unit.unit_data.len(),
+                    valid: unit.valid,
+                }
+            })
+            .collect();
+
+        let metadata = MapMetadata {
+            width: header.width,
+            height: header.height,
+            rows: header.rows,
+            cols: header.cols,
+            unit_flags,
+            mask_count: self.masks.len(),
+            units,
+        };
+        // 只有数字、字符串和布尔值，不会序列化失败
+        serde_json::to_string(&metadata).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::new_map::decode_from_bytes;
+
+    #[test]
+    fn metadata_is_one_json_line() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let json = map.to_metadata_json();
+        assert!(!json.contains('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["width"], fixture::WIDTH);
+        assert_eq!(value["unit_flags"]["2GPJ"], 1);
+        assert_eq!(value["mask_count"], 1);
+        assert_eq!(value["units"][0]["offset"], map.map_header.map_index_list[0]);
+        assert!(value["units"][0].get("unit_data").is_none());
+    }
+}
@@ -1,302 +1,1617 @@
 use std::{
-    fs::{self},
-    io::{Cursor, Seek, SeekFrom},
+    borrow::Cow,
+    fs::{self, File},
+    io::{BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom},
+    mem,
+    ops::Range,
+    path::Path,
+    time::{Duration, Instant},
 };
 
-use image::{Rgba, RgbaImage};
+use anyhow::Context;
+use image::{DynamicImage, RgbaImage};
 
 use crate::buffer_utils;
+use crate::decoder::TileDecoder;
+use crate::error::MapError;
+use crate::jpeg::{self, jpeg_dimensions, JPEG_EOI, JPEG_SOI};
+use crate::layout::{Layout, Section};
+use crate::mask::{read_mask, read_mask_table};
+use crate::parser::ParserContext;
+use crate::render::{TILE_HEIGHT, TILE_WIDTH};
+
+pub use crate::mask::{
+    decode_mask_bits, decode_mask_cells, decode_mask_image, Mask, MaskCell, MaskGrid, MaskKind,
+    MaskTable,
+};
+
+/// 地图文件版本，由文件开头的 4 字节标识
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapVersion {
+    /// 旧版地图，文件标识为 "XPAM" (MAPX)，宽高各是一个 u16
+    MapX,
+    /// 新版地图，文件标识为 "0.1M" (M1.0)
+    M1,
+}
+
+impl MapVersion {
+    /// 根据文件开头的 4 字节识别版本
+    pub fn from_magic(magic: &[u8]) -> Option<MapVersion> {
+        match magic {
+            b"XPAM" => Some(MapVersion::MapX),
+            b"0.1M" => Some(MapVersion::M1),
+            _ => None,
+        }
+    }
+
+    /// 文件头中宽高字段的宽度
+    pub fn dimension_format(&self) -> DimensionFormat {
+        match self {
+            MapVersion::MapX => DimensionFormat::U16,
+            MapVersion::M1 => DimensionFormat::U32,
+        }
+    }
+
+    /// 宽高后面、单元索引表前面多出来的字段的字节数，目前已知的版本都没有。
+    /// 以后遇到在宽高后面加了字段的版本，在这里加上对应的长度即可
+    pub fn extra_header_len(&self) -> u32 {
+        match self {
+            MapVersion::MapX => 0,
+            MapVersion::M1 => 0,
+        }
+    }
+
+    /// 单元索引表之前的文件头字节数 (标识、宽高和多出来的字段)
+    pub fn header_size(&self) -> u32 {
+        MAGIC_SIZE + self.dimension_format().size() + self.extra_header_len()
+    }
+}
+
+/// 文件头中宽高字段的宽度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DimensionFormat {
+    /// 宽高各是一个 u32，M1.0 文件都是这种
+    #[default]
+    U32,
+    /// 宽高各是一个 u16，旧版的 MAPX 文件是这种，文件头少 4 个字节
+    U16,
+}
+
+impl DimensionFormat {
+    /// 宽高两个字段一共的字节数
+    pub fn size(&self) -> u32 {
+        match self {
+            DimensionFormat::U32 => 8,
+            DimensionFormat::U16 => 4,
+        }
+    }
+}
+
+/// 单元在索引表中的排列顺序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TileOrder {
+    /// 一行一行排列，目前见过的文件都是这种
+    #[default]
+    RowMajor,
+    /// 一列一列排列，渲染出来像是转置了的文件可以试试这个
+    ColMajor,
+}
 
 /// 地图文件头
+///
+/// 以后可能会加字段，所以外部不能用结构体字面量构造，请用 MapHeader::new
+#[derive(Clone)]
+#[non_exhaustive]
 pub struct MapHeader {
-    pub flag: u32,
+    /// 文件开头的 4 字节标识，原样保存，只用来识别版本
+    pub magic: [u8; 4],
+    /// 由 magic 识别出的版本。M1.0 的文件头在标识后面直接是宽高，没有单独的标志位字段
+    pub version: MapVersion,
     pub width: u32,
     pub height: u32,
+    /// 单元索引表之前的文件头字节数，由版本和宽高字段的宽度决定，见 MapVersion::header_size
+    pub header_size: u32,
+    /// 宽高字段的宽度，由版本决定 (见 MapVersion::dimension_format)，encode_raw 按这个格式写回宽高
+    pub dimension_format: DimensionFormat,
     pub map_index_list: Vec<u32>,
     pub rows: u32,
     pub cols: u32,
     pub index_size: u32,
+    /// 单元的排列顺序，文件里没有记录，解码时总是 RowMajor，需要时手动修改
+    pub tile_order: TileOrder,
+}
+
+impl MapHeader {
+    /// 按宽高构造 M1.0 的文件头，索引表中的偏移都是 0，写成文件时由 encode 填充
+    pub fn new(width: u32, height: u32) -> crate::Result<MapHeader> {
+        let (rows, cols) = grid_size(width, height);
+        let index_size = buffer_utils::checked_mul(rows, cols, "index size")?;
+        Ok(MapHeader {
+            magic: *b"0.1M",
+            version: MapVersion::M1,
+            width,
+            height,
+            header_size: MapVersion::M1.header_size(),
+            dimension_format: MapVersion::M1.dimension_format(),
+            map_index_list: vec![0; index_size as usize],
+            rows,
+            cols,
+            index_size,
+            tile_order: TileOrder::RowMajor,
+        })
+    }
+
+    /// 获取第 row 行第 col 列的单元在文件中的偏移，越界时返回 None
+    pub fn offset_of(&self, row: u32, col: u32) -> Option<u32> {
+        if row >= self.rows || col >= self.cols {
+            return None;
+        }
+        self.map_index_list.get(self.slot_of(row, col)).copied()
+    }
+
+    /// 第 row 行第 col 列的单元在索引表中的位置，不检查越界
+    pub fn slot_of(&self, row: u32, col: u32) -> usize {
+        match self.tile_order {
+            TileOrder::RowMajor => row as usize * self.cols as usize + col as usize,
+            TileOrder::ColMajor => col as usize * self.rows as usize + row as usize,
+        }
+    }
+
+    /// 和像素区域 (x, y, w, h) 有重叠的所有单元的 slot，按行优先的顺序排列。
+    /// 区域可以从单元中间开始，超出地图的部分会被忽略
+    pub fn bounding_tiles(&self, x: u32, y: u32, w: u32, h: u32) -> Vec<usize> {
+        let right = x.saturating_add(w).min(self.width);
+        let bottom = y.saturating_add(h).min(self.height);
+        if x >= right || y >= bottom {
+            return vec![];
+        }
+
+        let (first_row, last_row) = (y / TILE_HEIGHT, (bottom - 1) / TILE_HEIGHT);
+        let (first_col, last_col) = (x / TILE_WIDTH, (right - 1) / TILE_WIDTH);
+        let mut slots = vec![];
+        for row in first_row..=last_row.min(self.rows.saturating_sub(1)) {
+            for col in first_col..=last_col.min(self.cols.saturating_sub(1)) {
+                slots.push(self.slot_of(row, col));
+            }
+        }
+        slots
+    }
+
+    /// 单元索引表在文件中的字节 (小端 u32)。索引表读取时没有做任何修改，
+    /// 所以和文件中从 header_size 开始的字节完全一样 (被截断的索引表只包含实际存在的部分)
+    pub fn index_bytes(&self) -> Vec<u8> {
+        self.map_index_list
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect()
+    }
+
+    /// 所有单元在文件中的偏移，从小到大排序
+    pub fn tile_offsets(&self) -> Vec<u32> {
+        let mut offsets = self.map_index_list.clone();
+        offsets.sort_unstable();
+        offsets
+    }
+
+    /// 和 tile_offsets 一样按偏移排序，但保留每个偏移在索引表中的 slot
+    pub fn tile_offsets_with_slots(&self) -> Vec<(usize, u32)> {
+        let mut offsets = self
+            .map_index_list
+            .iter()
+            .copied()
+            .enumerate()
+            .collect::<Vec<_>>();
+        offsets.sort_by_key(|(_, offset)| *offset);
+        offsets
+    }
+
+    /// 索引表中第 slot 个单元所在的 (行, 列)
+    pub fn position_of(&self, slot: usize) -> (u32, u32) {
+        match self.tile_order {
+            TileOrder::RowMajor => {
+                let cols = self.cols.max(1) as usize;
+                ((slot / cols) as u32, (slot % cols) as u32)
+            }
+            TileOrder::ColMajor => {
+                let rows = self.rows.max(1) as usize;
+                ((slot % rows) as u32, (slot / rows) as u32)
+            }
+        }
+    }
 }
 
 /// 地图单元数据（小图片）
+///
+/// 以后可能会加字段，所以外部不能用结构体字面量构造，请用 Unit::new
+#[derive(Clone)]
+#[non_exhaustive]
 pub struct Unit {
+    /// 在索引表中的位置，行优先，单独用 decode_unit_at 读取时为 0
+    pub slot: usize,
     pub unit_flag: String,
     pub size: u32,
     pub unit_data: Vec<u8>,
+    /// 解码后的图片，只有开启 `eager-images` 特性时才会在 decode 时填充
+    pub image: Option<DynamicImage>,
+    /// 解码时检查的结果: JPEG 首尾标记完整，或者是 PNG。为 false 的单元大概率解不出图片
+    pub valid: bool,
+    /// 单元前缀中的数据: 和这个单元有重叠的遮罩在遮罩表中的下标。
+    /// 以前以为是单元的透明度信息，对照真实地图确认过并不是，单元的镂空要靠遮罩
+    pub mask_indices: Vec<u32>,
+    /// GEPJ 单元的扫描数据中有重启标记 (FFD0 到 FFD7)，修复时已经原样保留
+    pub restart_markers: bool,
+    /// 开启 DecodeOptions::preserve_raw 时记录的原始字节，包括前缀、类型、大小和没修复过的数据
+    pub raw: Option<RawSpan>,
+}
+
+/// 原样保留的一段文件字节
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawSpan {
+    /// 在文件中的偏移
+    pub offset: u64,
+    pub bytes: Vec<u8>,
 }
 
-/// 遮罩数据
-pub struct Mask {
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
-    size: u32,
-    data: Vec<u8>,
+impl RawSpan {
+    /// 在文件中占的字节区间
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.bytes.len() as u64
+    }
+}
+
+impl Unit {
+    /// 用已经是完整图片的数据构造单元，slot 为 0，valid 按数据的首尾标记计算
+    pub fn new(unit_flag: impl Into<String>, size: u32, unit_data: Vec<u8>) -> Unit {
+        let mut unit = Unit {
+            slot: 0,
+            unit_flag: unit_flag.into(),
+            size,
+            unit_data,
+            image: None,
+            valid: false,
+            mask_indices: vec![],
+            restart_markers: false,
+            raw: None,
+        };
+        unit.valid = unit.is_valid_jpeg() || unit.unit_data.starts_with(&PNG_SIGNATURE);
+        unit
+    }
+
+    /// 把单元数据解码成图片，失败时错误信息里带上单元的位置、类型和大小
+    pub fn decode(&self) -> anyhow::Result<DynamicImage> {
+        image::load_from_memory(&self.unit_data).with_context(|| self.decode_error())
+    }
+
+    /// 和 decode 一样，但是用指定的解码器
+    pub fn decode_with(&self, decoder: &dyn TileDecoder) -> anyhow::Result<RgbaImage> {
+        decoder.decode(&self.unit_data).with_context(|| self.decode_error())
+    }
+
+    fn decode_error(&self) -> String {
+        format!(
+            "Failed to decode unit {} ({}, {} bytes)",
+            self.slot,
+            self.unit_flag,
+            self.unit_data.len()
+        )
+    }
+
+    /// 只解析 JPEG 的 SOF 段 (或者 PNG 的 IHDR) 得到单元图片的宽高，比 decode 快得多。
+    /// GEPJ 单元在读取时已经修复过，和普通 JPEG 一样解析
+    pub fn dimensions(&self) -> anyhow::Result<(u32, u32)> {
+        let data = &self.unit_data;
+        if data.starts_with(&PNG_SIGNATURE) {
+            // 签名之后是 IHDR 块: 长度、类型、宽、高
+            if let Some(ihdr) = data.get(16..24) {
+                let width = u32::from_be_bytes(ihdr[0..4].try_into()?);
+                let height = u32::from_be_bytes(ihdr[4..8].try_into()?);
+                return Ok((width, height));
+            }
+        } else if let Some(dimensions) = jpeg_dimensions(data) {
+            return Ok(dimensions);
+        }
+        Err(anyhow::anyhow!(
+            "Unit {} ({}, {} bytes) has no readable image header",
+            self.slot,
+            self.unit_flag,
+            data.len()
+        ))
+    }
+
+    /// 获取单元图片，已经解码过的直接借用，否则现场解码
+    pub fn load_image(&self) -> anyhow::Result<Cow<'_, DynamicImage>> {
+        match &self.image {
+            Some(image) => Ok(Cow::Borrowed(image)),
+            None => Ok(Cow::Owned(self.decode()?)),
+        }
+    }
+
+    /// 数据以 FFD8 开头、FFD9 结尾，只检查首尾标记，不保证一定能解码
+    pub fn is_valid_jpeg(&self) -> bool {
+        self.unit_data.starts_with(&JPEG_SOI) && self.unit_data.ends_with(&JPEG_EOI)
+    }
+
+    /// 以 Read 的方式读取单元的 JPEG 数据，可以直接 io::copy 到文件或网络中
+    pub fn reader(&self) -> impl Read + '_ {
+        Cursor::new(self.unit_data.as_slice())
+    }
 }
 
 /// 地图数据
+///
+/// Map 只包含普通数据，保证是 `Send + Sync` 的，可以放心在线程之间传递或共享，
+/// 以后加缓存之类的字段也不能破坏这一点 (有测试保证)
 pub struct Map {
     pub map_header: MapHeader,
+    /// 按 map_index_list 的顺序排列 (slot 递增)，被跳过的单元没有占位。
+    /// 这个顺序是有保证的，以后并行解码也要按 slot 排好序再返回
     pub units: Vec<Unit>,
+    /// 按遮罩偏移表的顺序排列，第 i 个遮罩的 index() 就是 i，也是绘制顺序。
+    /// 只保存解压后的 2 bit 格子数据，解码时不会生成任何图片。
+    /// 只需要遮挡信息时用 decode_mask_bits，需要可视化时再用 decode_mask_image
     pub masks: Vec<Mask>,
+    /// 遮罩表开头那个含义未知的 u32
+    pub mask_table_flag: u32,
+    /// 解析时读取过的字节区间
+    pub layout: Layout,
+    /// 解析时发现的不影响继续解码的问题
+    pub warnings: Vec<String>,
+    /// decode_until 提前停止时为 true，这时 units 只包含停止之前读到的单元
+    pub partial: bool,
+    /// 开启 DecodeOptions::preserve_raw 时，不属于文件头、遮罩表、单元和遮罩的字节，
+    /// 和单元、遮罩的原始字节一起可以用 encode_raw 还原出一模一样的文件
+    pub raw_gaps: Vec<RawSpan>,
 }
 
-/// 读取文件头
-fn read_header(file: &mut Cursor<Vec<u8>>) -> anyhow::Result<MapHeader> {
-    let flag_bytes = buffer_utils::read_bytes(file, 4)?;
-    let flag_str = String::from_utf8(flag_bytes.clone())?;
+impl Map {
+    /// 用文件头、单元和遮罩构造地图，其余的解析信息都是空的，可以直接 encode 成文件。
+    /// units 和 masks 要按文档中的顺序排列 (见 units 和 masks 字段)
+    pub fn new(map_header: MapHeader, units: Vec<Unit>, masks: Vec<Mask>) -> Map {
+        Map {
+            map_header,
+            units,
+            masks,
+            mask_table_flag: 0,
+            layout: Layout::default(),
+            warnings: vec![],
+            partial: false,
+            raw_gaps: vec![],
+        }
+    }
 
-    if flag_str != "0.1M" {
-        return Err(anyhow::anyhow!("Invalid map file"));
+    /// 地图文件头，等同于 &self.map_header
+    pub fn header(&self) -> &MapHeader {
+        &self.map_header
     }
 
-    let flag = u32::from_le_bytes([flag_bytes[0], flag_bytes[1], flag_bytes[2], flag_bytes[3]]);
-    let width = buffer_utils::read_u32(file)?;
-    let height = buffer_utils::read_u32(file)?;
+    /// 地图的像素宽高
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.map_header.width, self.map_header.height)
+    }
+
+    /// 单元网格的 (行数, 列数)
+    pub fn tile_grid(&self) -> (u32, u32) {
+        (self.map_header.rows, self.map_header.cols)
+    }
 
+    /// 宽或高为 0 的地图索引表是空的，没有任何单元，渲染出来是 0x0 的图片
+    pub fn is_empty(&self) -> bool {
+        self.map_header.map_index_list.is_empty()
+    }
+
+    /// 估算这张地图在内存中占用的字节数，包括单元数据、已解码的图片、遮罩数据、
+    /// 索引表和各个结构体本身，不包括分配器的额外开销
+    pub fn memory_footprint(&self) -> usize {
+        let header = self.map_header.map_index_list.capacity() * mem::size_of::<u32>();
+        let units = self.units.capacity() * mem::size_of::<Unit>()
+            + self
+                .units
+                .iter()
+                .map(|unit| {
+                    unit.unit_data.capacity()
+                        + unit.unit_flag.capacity()
+                        + unit.mask_indices.capacity() * mem::size_of::<u32>()
+                        + unit.raw.as_ref().map_or(0, |raw| raw.bytes.capacity())
+                        + unit.image.as_ref().map_or(0, |image| image.as_bytes().len())
+                })
+                .sum::<usize>();
+        let masks = self.masks.capacity() * mem::size_of::<Mask>()
+            + self
+                .masks
+                .iter()
+                .map(|mask| {
+                    mask.data.capacity() + mask.raw.as_ref().map_or(0, |raw| raw.bytes.capacity())
+                })
+                .sum::<usize>();
+        mem::size_of::<Map>() + header + units + masks
+    }
+
+    /// 索引表中有可用单元 (valid 为 true) 的格子所占的比例，0.0 到 1.0。
+    /// 类型未知或者损坏被跳过的单元会让地图出现空洞，没有格子的空地图算作 1.0
+    pub fn coverage(&self) -> f32 {
+        let slots = self.map_header.map_index_list.len();
+        if slots == 0 {
+            return 1.0;
+        }
+        let mut covered = vec![false; slots];
+        for unit in self.units.iter().filter(|unit| unit.valid) {
+            if let Some(slot) = covered.get_mut(unit.slot) {
+                *slot = true;
+            }
+        }
+        covered.iter().filter(|c| **c).count() as f32 / slots as f32
+    }
+
+    /// 拆成文件头、单元和遮罩，其余的解析信息 (layout、warnings 等) 会被丢弃
+    pub fn into_parts(self) -> (MapHeader, Vec<Unit>, Vec<Mask>) {
+        (self.map_header, self.units, self.masks)
+    }
+}
+
+/// 宽高对应的单元行数和列数，边上不满一个单元的也算一个
+pub(crate) fn grid_size(width: u32, height: u32) -> (u32, u32) {
     let rows = ((height as f32) / 240.00).ceil() as u32;
     let cols = ((width as f32) / 320.00).ceil() as u32;
-    let index_size = rows * cols;
-    let index_bytes = buffer_utils::read_bytes(file, (index_size * 4) as usize)?;
-    let map_index_list = index_bytes
+    (rows, cols)
+}
+
+/// 文件开头标识的字节数
+const MAGIC_SIZE: u32 = 4;
+
+/// 读取文件头
+pub(crate) fn read_header<R: Read + Seek>(
+    file: &mut ParserContext<R>,
+) -> anyhow::Result<MapHeader> {
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+
+    let version = match MapVersion::from_magic(&magic) {
+        Some(version) => version,
+        None => return Err(anyhow::anyhow!("Invalid map file")),
+    };
+    read_header_fields(file, magic, version, version.extra_header_len())
+}
+
+/// 读取标识后面的文件头，extra_len 是宽高后面要跳过的字节数
+fn read_header_fields<R: Read + Seek>(
+    file: &mut ParserContext<R>,
+    magic: [u8; 4],
+    version: MapVersion,
+    extra_len: u32,
+) -> anyhow::Result<MapHeader> {
+    // 宽高字段的宽度由版本决定，不按读出来的值猜
+    let dimension_format = version.dimension_format();
+    let (width, height) = match dimension_format {
+        DimensionFormat::U32 => (buffer_utils::read_u32(file)?, buffer_utils::read_u32(file)?),
+        DimensionFormat::U16 => {
+            let width = buffer_utils::read_u16_le(file)?;
+            let height = buffer_utils::read_u16_le(file)?;
+            (width as u32, height as u32)
+        }
+    };
+    if extra_len > 0 {
+        file.seek(SeekFrom::Current(extra_len as i64))?;
+    }
+    let header_size = MAGIC_SIZE + dimension_format.size() + extra_len;
+
+    let (rows, cols) = grid_size(width, height);
+    let index_size = buffer_utils::checked_mul(rows, cols, "index size")?;
+    let index_len = buffer_utils::checked_mul(index_size, 4, "index table length")?;
+    if index_len as u64 > file.remaining()? {
+        return Err(anyhow::anyhow!(
+            "Index table of {} bytes ({}x{} tiles) exceeds the file",
+            index_len,
+            rows,
+            cols
+        ));
+    }
+    let index_bytes = buffer_utils::read_bytes(file, index_len as usize)?;
+    let mut map_index_list = index_bytes
         .chunks(4)
         .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
-        .collect();
+        .collect::<Vec<u32>>();
+
+    // 有些文件按宽高算出来的单元数比实际存的多，多读的部分其实是遮罩表。
+    // 真正的单元偏移一定在索引表之后，第一个指向索引表内部的值就是索引表的结尾
+    let actual = map_index_list
+        .iter()
+        .enumerate()
+        .position(|(i, offset)| (*offset as u64) < header_size as u64 + (i as u64 + 1) * 4)
+        .unwrap_or(map_index_list.len());
+    let index_size = if actual < map_index_list.len() {
+        file.warnings.push(format!(
+            "Index table has {} entries, but {}x{} tiles are expected",
+            actual, rows, cols
+        ));
+        map_index_list.truncate(actual);
+        file.seek(SeekFrom::Start(header_size as u64 + actual as u64 * 4))?;
+        actual as u32
+    } else {
+        index_size
+    };
 
     Ok(MapHeader {
-        flag,
+        magic,
+        version,
         width,
         height,
+        header_size,
+        dimension_format,
         map_index_list,
         rows,
         cols,
         index_size,
+        tile_order: TileOrder::RowMajor,
     })
 }
 
-/// 读取遮罩数据 (遮罩的图片是被压缩的，需要解压)
-/// 这个方法应该是有问题的
-fn read_mask(file: &mut Cursor<Vec<u8>>) -> anyhow::Result<Vec<Mask>> {
-    let unknown = buffer_utils::read_u32(file)?;
-    let mask_num = buffer_utils::read_u32(file)?;
-    let mask_data = buffer_utils::read_bytes(file, (mask_num * 4) as usize)?;
-    let masks_offsets = mask_data
-        .chunks(4)
-        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
-        .collect::<Vec<u32>>();
-
-    let mut masks = Vec::new();
-    for offset in masks_offsets {
-        file.seek(SeekFrom::Start(offset as u64))?;
+/// 读取图片并转码
+/// 修复之前的数据读到 map_file.scratch 中，多个单元共用同一个缓冲区
+fn read_jpeg<R: Read + Seek>(
+    map_file: &mut ParserContext<R>,
+    unit: &mut Unit,
+) -> anyhow::Result<()> {
+    let mut raw = mem::take(&mut map_file.scratch);
+    raw.clear();
+    raw.resize(unit.size as usize, 0);
+    map_file.read_exact(&mut raw)?;
+    let restarts = !map_file.options.ignore_restart_markers;
+    let fix = jpeg::fix_jpeg_with(&raw, &mut unit.unit_data, restarts);
+    unit.restart_markers = fix.restart_markers > 0;
+    map_file.scratch = raw;
+    Ok(())
+}
 
-        let x = buffer_utils::read_u32(file)?;
-        let y = buffer_utils::read_u32(file)?;
-        let width = buffer_utils::read_u32(file)?;
-        let height = buffer_utils::read_u32(file)?;
-        let size = buffer_utils::read_u32(file)?;
-        let data = buffer_utils::read_bytes(file, (size) as usize)?;
+/// 认识的单元类型标识，用来判断单元是否省略了前缀
+pub(crate) const KNOWN_UNIT_FLAGS: [&[u8; 4]; 2] = [b"GEPJ", b"2GPJ"];
 
-        let aiginw = ((width >> 2) + if (width % 4 != 0) { 1 } else { 0 }) << 2;
-        let mask_index = (aiginw * height) >> 2;
+/// 读取单元类型标识前面的前缀: 数量 n 和 n 个 u32 的遮罩下标
+fn read_unit_prefix<R: Read + Seek>(
+    map_file: &mut ParserContext<R>,
+    offset: u32,
+) -> anyhow::Result<Vec<u32>> {
+    let count = buffer_utils::read_u32(map_file)?;
+    let len = buffer_utils::checked_mul(count, 4, "unit prefix length")?;
+    if len as u64 > map_file.remaining()? {
+        return Err(anyhow::anyhow!(
+            "Unit prefix of {} bytes at offset {} exceeds the file",
+            len,
+            offset
+        ));
+    }
+    let mut bytes = vec![0u8; len as usize];
+    map_file.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
 
-        let mut decompressed_data = vec![0; mask_index as usize];
-        let out = decompressed_data.as_mut_slice();
-        let out = rust_lzo::LZOContext::decompress_to_slice(data.as_slice(), out);
+/// PNG 文件开头的签名
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
 
-        if out.1 != rust_lzo::LZOError::OK {
-            return Err(anyhow::anyhow!("Decompress mask data failed"));
+/// 2GPJ 按理说是完整的 JPEG，但有些文件在 SOI 前面还有填充字节，这里把它们去掉
+fn fix_2gpj(unit: &mut Unit, offset: u32, warnings: &mut Vec<String>) {
+    if unit.unit_data.starts_with(&JPEG_SOI) {
+        return;
+    }
+    match unit.unit_data.windows(2).position(|w| w == JPEG_SOI) {
+        Some(start) => {
+            warnings.push(format!(
+                "2GPJ unit at offset {} has {} bytes before SOI, stripped",
+                offset, start
+            ));
+            unit.unit_data.drain(..start);
         }
+        None => warnings.push(format!("2GPJ unit at offset {} has no JPEG SOI marker", offset)),
+    }
+}
 
-        let mut mask_data: Vec<i64> = vec![0; (width * height) as usize];
-        let mut desc: usize = 0;
-        for k in 0..height {
-            for i in 0..width {
-                let index = (k * aiginw + i) << 1 as usize;
-                let mask = out.0[(index >> 3) as usize];
-                let mask = mask >> (index % 8);
-                if mask & 3 == 3 {
-                    mask_data[desc] = 0xF0;
-                }
+/// 读取偏移处的单元，不认识的类型返回 None
+pub(crate) fn read_unit_at<R: Read + Seek>(
+    map_file: &mut ParserContext<R>,
+    slot: usize,
+    offset: u32,
+) -> anyhow::Result<Option<Unit>> {
+    let mut unit = Unit {
+        slot,
+        unit_flag: "".to_string(),
+        size: 0,
+        unit_data: vec![],
+        image: None,
+        valid: false,
+        mask_indices: vec![],
+        restart_markers: false,
+        raw: None,
+    };
 
-                desc += 1;
-            }
+    map_file.seek_to(offset as u64, "Unit")?;
+
+    // 有的文件单元省略了前缀 (数量 n 和 n 个遮罩下标)，直接从类型标识开始。
+    // 目前没发现这和版本有关，所以只看开头: 是认识的类型 (GEPJ/2GPJ) 时不读前缀
+    let mut head = [0u8; 4];
+    map_file.read_exact(&mut head)?;
+    map_file.seek_to(offset as u64, "Unit")?;
+    if !KNOWN_UNIT_FLAGS.contains(&&head) {
+        unit.mask_indices = read_unit_prefix(map_file, offset)?;
+    }
+
+    let unit_head = buffer_utils::read_bytes(map_file, 8)?;
+    unit.unit_flag = String::from_utf8(unit_head[0..4].to_vec())?;
+    unit.size = u32::from_le_bytes(unit_head[4..8].try_into()?);
+    let available = map_file.remaining()?;
+    if unit.size as u64 > available {
+        return Err(MapError::UnitTooLarge {
+            slot,
+            size: unit.size,
+            available,
         }
+        .into());
+    }
+    let mut unit = match read_unit_data(map_file, unit, offset)? {
+        Some(unit) => unit,
+        None => return Ok(None),
+    };
+    if map_file.options.preserve_raw {
+        unit.raw = Some(map_file.raw_span(offset as u64)?);
+    }
+    Ok(Some(unit))
+}
 
-        let mut image = RgbaImage::new(width, height);
-        for (x, y, pixel) in image.enumerate_pixels_mut() {
-            let index = y * width + x;
-            let color = mask_data[index as usize];
-            let r = ((color >> 11) & 0x1F) << 3;
-            let g = ((color >> 5) & 0x3F) << 2;
-            let b = (color & 0x1F) << 3;
-            let a = ((color >> 16) & 0x1F) << 3;
-            *pixel = Rgba([r as u8, g as u8, b as u8, a as u8]);
+/// 按单元类型读取类型和大小后面的数据，不认识的类型返回 None
+fn read_unit_data<R: Read + Seek>(
+    map_file: &mut ParserContext<R>,
+    mut unit: Unit,
+    offset: u32,
+) -> anyhow::Result<Option<Unit>> {
+    if unit.unit_flag == "GEPJ" {
+        // 这种类型的的图片要进行解码
+        read_jpeg(map_file, &mut unit)?;
+        Ok(Some(unit))
+
+    // 这里是参考了SeeMap这个软件的源码才知道有一个 2GPJ 的类型
+    } else if unit.unit_flag == "2GPJ" {
+        // 这种类型的的图片是完整的jpeg
+        unit.unit_data = buffer_utils::read_bytes(map_file, unit.size as usize)?;
+        if !unit.unit_data.starts_with(&PNG_SIGNATURE) {
+            fix_2gpj(&mut unit, offset, &mut map_file.warnings);
+        }
+        Ok(Some(unit))
+    } else if unit.size as usize >= PNG_SIGNATURE.len() {
+        // 不认识的类型，如果数据本身是 PNG 就当作图片，不需要任何修复
+        let signature = buffer_utils::read_bytes(map_file, PNG_SIGNATURE.len())?;
+        if signature != PNG_SIGNATURE {
+            return Ok(None);
         }
+        let rest = buffer_utils::read_bytes(map_file, unit.size as usize - signature.len())?;
+        unit.unit_data = [signature, rest].concat();
+        Ok(Some(unit))
+    } else {
+        Ok(None)
+    }
+}
 
-        image.save(format!("masks/{}.png", offset)).unwrap();
+/// 读取文件中指定偏移处的单元，适合已经拿到索引表只想提取某个单元的场景。
+/// 不知道单元在索引表中的位置，返回的单元 slot 为 0
+pub fn decode_unit_at<R: Read + Seek>(r: &mut R, offset: u32) -> anyhow::Result<Unit> {
+    read_unit_at(&mut ParserContext::new(r, DecodeOptions::default())?, 0, offset)?
+        .ok_or_else(|| anyhow::anyhow!("Unknown unit flag at offset {}", offset))
+}
 
-        let mask = Mask {
-            x,
-            y,
-            width,
-            height,
-            size,
-            data: out.0.to_vec(),
-        };
+/// 单个单元的解码统计
+#[derive(Debug, Clone)]
+pub struct UnitMetrics {
+    pub slot: usize,
+    /// 单元在文件中的偏移
+    pub offset: u32,
+    /// 文件中记录的数据大小
+    pub raw_size: u32,
+    /// 修复之后的 JPEG 大小
+    pub fixed_size: usize,
+    /// 读取和修复所用的时间
+    pub duration: Duration,
+}
+
+/// 解码选项，传给 decode_with_options，默认值和 decode 的行为一致
+///
+/// ```ignore
+/// let options = DecodeOptions::default().strict(true);
+/// let map = decode_with_options("1001.map", &options)?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub(crate) strict: bool,
+    pub(crate) skip_bad_units: bool,
+    pub(crate) preserve_raw: bool,
+    pub(crate) ignore_restart_markers: bool,
+}
+
+impl DecodeOptions {
+    /// 文件中有没被解析到的字节或者位置对不上时返回错误，用来发现还没支持的格式内容
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
 
-        masks.push(mask);
+    /// 单元头损坏时记录到 warnings 中并跳过这个单元，而不是整个解码失败
+    pub fn skip_bad_units(mut self, skip_bad_units: bool) -> Self {
+        self.skip_bad_units = skip_bad_units;
+        self
     }
 
-    Ok(masks)
+    /// 保留单元、遮罩和其余字节的原始数据，之后可以用 encode_raw 逐字节还原文件，
+    /// 内存占用大约会多一份文件的大小
+    pub fn preserve_raw(mut self, preserve_raw: bool) -> Self {
+        self.preserve_raw = preserve_raw;
+        self
+    }
+
+    /// 修复 GEPJ 单元时不处理 DRI 段，扫描数据中的每个 FF 后面都补 00 (旧的行为)，
+    /// 用于个别 DRI 段有问题、按重启标记处理反而解不出来的地图
+    pub fn ignore_restart_markers(mut self, ignore_restart_markers: bool) -> Self {
+        self.ignore_restart_markers = ignore_restart_markers;
+        self
+    }
 }
 
-/// 读取图片并转码
-fn read_jpeg(map_file: &mut Cursor<Vec<u8>>, unit: &mut Unit) -> anyhow::Result<()> {
-    unit.unit_data = buffer_utils::read_bytes(map_file, unit.size as usize)?;
-
-    // 这段代码的逻辑是参考 https://www.jianshu.com/p/7faf26c9648a 实现的
-    let mut is_ffda = false;
-    for index in 0..unit.unit_data.len() {
-        if !is_ffda {
-            if unit.unit_data[index] == 0xFF && unit.unit_data[index + 1] == 0xDA {
-                unit.unit_data[index + 3] = 0x0C;
-
-                // +13位的意思是说： index当前是ff的位置， ff后面总共还有12位数据，其中 DA 1位， 长度2位， 9位数据
-                unit.unit_data.insert(index + 13, 0x00);
-                unit.unit_data.insert(index + 14, 0x3F);
-                unit.unit_data.insert(index + 15, 0x00);
-                is_ffda = true;
+/// 解码统计，用来找出解码特别慢的单元
+#[derive(Debug, Clone, Default)]
+pub struct DecodeMetrics {
+    pub units: Vec<UnitMetrics>,
+}
+
+/// 读取每一个单元的数据
+fn read_unit<R: Read + Seek>(
+    map_header: &MapHeader,
+    map_file: &mut ParserContext<R>,
+    until: Option<&dyn Fn(&Unit) -> bool>,
+) -> anyhow::Result<(Vec<Unit>, bool)> {
+    let mut units: Vec<Unit> = vec![];
+
+    let slot_count = map_header.map_index_list.len();
+    for (slot, index) in map_header.map_index_list.iter().enumerate() {
+        let start = Instant::now();
+        let unit = match read_unit_at(map_file, slot, *index) {
+            Ok(unit) => unit,
+            Err(e) if map_file.options.skip_bad_units => {
+                let message = format!("Unit {} at offset {} skipped: {}", slot, index, e);
+                map_file.warnings.push(message);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let end = map_file.position()?;
+        map_file.layout.push(Section::Unit(slot), *index as u64..end);
+        if let (Some(metrics), Some(unit)) = (map_file.metrics.as_mut(), unit.as_ref()) {
+            metrics.units.push(UnitMetrics {
+                slot,
+                offset: *index,
+                raw_size: unit.size,
+                fixed_size: unit.unit_data.len(),
+                duration: start.elapsed(),
+            });
+        }
+        if let Some(mut unit) = unit {
+            map_file.charge(unit.unit_data.len() as u64)?;
+            unit.valid = unit.is_valid_jpeg() || unit.unit_data.starts_with(&PNG_SIGNATURE);
+            if !unit.valid {
+                let message = format!("Unit {} does not look like a complete image", slot);
+                map_file.warnings.push(message);
             }
-        } else {
-            if unit.unit_data[index] == 0xFF {
-                if unit.unit_data[index + 1] == 0xD9 {
-                    break;
+            if cfg!(feature = "eager-images") {
+                // 解码失败的单元留到用的时候再报错
+                match unit.decode() {
+                    Ok(image) => unit.image = Some(image),
+                    Err(e) => map_file.warnings.push(format!("{:#}", e)),
                 }
-                unit.unit_data.insert(index + 1, 0x00);
+            }
+            let stop = until.map_or(false, |until| until(&unit));
+            units.push(unit);
+            if stop {
+                // 最后一个单元满足条件时其实已经读完了
+                return Ok((units, slot + 1 < slot_count));
             }
         }
     }
+    Ok((units, false))
+}
 
-    // 这段代码是参考一个C#版本实现的,和上面的有些类似，
-    // 但是逻辑上是不一样的， 不过上面的代码也能实现同样的功能，还不知道为什么，先注释测试再看吧
-
-    // let mut is_filled = false;
-    // let jpeg_buffer = vec![];
-    // let mut jpeg_buffer = Cursor::new(jpeg_buffer);
-    // jpeg_buffer.write(&unit.unit_data[0..2])?;
-
-    // let mut p: usize = 4;
-    // let mut start = 4;
-
-    // while p < (unit.size - 2) as usize {
-    //     if !is_filled && unit.unit_data[p] == 0xFF {
-    //         p = p + 1;
-    //         if unit.unit_data[p] == 0xDA {
-    //             is_filled = true;
-    //             unit.unit_data[p + 2] = 0x0C;
-    //             jpeg_buffer.write(&unit.unit_data[start as usize..p + 10])?;
-    //             let write_data: [u8; 3] = [0x00, 0x3F, 0x00];
-    //             jpeg_buffer.write(&write_data)?;
-    //             start = p + 10;
-    //             p = p + 9;
-    //         }
-    //     }
-
-    //     if is_filled && unit.unit_data[p] == 0xFF {
-    //         jpeg_buffer.write(&unit.unit_data[start as usize..p + 1])?;
-    //         let empty = [0x00; 1];
-    //         jpeg_buffer.write(&empty)?;
-    //         start = p + 1;
-    //     }
-
-    //     p = p + 1;
-    // }
-
-    // jpeg_buffer.write(&unit.unit_data[start as usize..(unit.size) as usize])?;
-    // unit.unit_data = jpeg_buffer.into_inner().to_vec();
-    Ok(())
+/// 只读取文件头和单元索引表，不读取遮罩和单元，是最快的查看方式
+pub fn decode_index(path: impl AsRef<Path>) -> anyhow::Result<MapHeader> {
+    let reader = BufReader::new(File::open(path)?);
+    read_header(&mut ParserContext::new(reader, DecodeOptions::default())?)
 }
 
-/// 读取每一个单元的数据
-fn read_unit(map_header: &MapHeader, map_file: &mut Cursor<Vec<u8>>) -> anyhow::Result<Vec<Unit>> {
-    let mut units: Vec<Unit> = vec![];
+/// 只读取文件头和遮罩偏移表，不解压任何遮罩，用来快速统计遮罩数量
+pub fn decode_mask_table(path: impl AsRef<Path>) -> anyhow::Result<MaskTable> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut file = ParserContext::new(reader, DecodeOptions::default())?;
+    read_header(&mut file)?;
+    read_mask_table(&mut file)
+}
 
-    for index in map_header.map_index_list.iter() {
-        let mut unit = Unit {
-            unit_flag: "".to_string(),
-            size: 0,
-            unit_data: vec![],
-        };
+/// 只读取文件开头的 4 字节判断是否为地图文件，不会加载整个文件
+pub fn peek_magic(path: &Path) -> std::io::Result<Option<MapVersion>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(MapVersion::from_magic(&magic)),
+        // 文件不足 4 字节，肯定不是地图文件
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
-        map_file.seek(SeekFrom::Start(*index as u64))?;
+/// gzip 文件开头的标识
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
 
-        // 这两个数据未知，不知道用来干什么的
-        let unkonwn = buffer_utils::read_u32(map_file)?;
-        let unkonwn_data = buffer_utils::read_bytes(map_file, (4 * unkonwn) as usize)?;
+/// 有些地方分发的是 gzip 压缩过的 `.map.gz`，以 gzip 标识开头的数据先解压
+fn unwrap_gzip(bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if bytes.starts_with(&GZIP_MAGIC) {
+        gunzip(&bytes)
+    } else {
+        Ok(bytes)
+    }
+}
 
-        let unit_head = buffer_utils::read_bytes(map_file, 8)?;
-        unit.unit_flag = String::from_utf8(unit_head[0..4].to_vec())?;
-        unit.size = u32::from_le_bytes(unit_head[4..8].try_into()?);
-        if unit.unit_flag == "GEPJ" {
-            // 这种类型的的图片要进行解码
-            read_jpeg(map_file, &mut unit)?;
-            units.push(unit);
+#[cfg(feature = "flate2")]
+fn gunzip(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut decompressed = vec![];
+    flate2::read::GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
 
-        // 这里是参考了SeeMap这个软件的源码才知道有一个 2GPJ 的类型
-        } else if unit.unit_flag == "2GPJ" {
-            // 这种类型的的图片是完整的jpeg
-            unit.unit_data = buffer_utils::read_bytes(map_file, unit.size as usize)?;
-            units.push(unit);
-        }
-    }
-    Ok(units)
+#[cfg(not(feature = "flate2"))]
+fn gunzip(_bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Err(anyhow::anyhow!(
+        "Map data is gzip compressed, enable the `flate2` feature to decode it"
+    ))
 }
 
 /// 读取地图文件到内存中
-fn load_mapfile(filename: &str) -> anyhow::Result<Cursor<Vec<u8>>> {
-    let mut file = fs::read(filename)?;
+fn load_mapfile(path: &Path) -> anyhow::Result<Cursor<Vec<u8>>> {
+    let file = unwrap_gzip(fs::read(path)?)?;
     let cursor = Cursor::new(file);
     Ok(cursor)
 }
 
-pub fn decode(filename: &str) -> anyhow::Result<Map> {
-    let mut bytes = load_mapfile(filename)?;
-    let header = read_header(&mut bytes)?;
-    let masks = read_mask(&mut bytes)?;
-    let uints = read_unit(&header, &mut bytes)?;
+/// 解码地图文件，gzip 压缩的文件会先解压
+pub fn decode(path: impl AsRef<Path>) -> anyhow::Result<Map> {
+    decode_with_options(path, &DecodeOptions::default())
+}
+
+/// 按 options 解码地图
+pub fn decode_with_options(path: impl AsRef<Path>, options: &DecodeOptions) -> anyhow::Result<Map> {
+    decode_cursor(load_mapfile(path.as_ref())?, options.clone(), None)
+}
+
+/// 解码地图，strict 为 true 时如果文件中有没被解析到的字节就返回错误，
+/// 用来发现还没支持的格式内容
+pub fn decode_with_strict(path: impl AsRef<Path>, strict: bool) -> anyhow::Result<Map> {
+    decode_with_options(path, &DecodeOptions::default().strict(strict))
+}
+
+/// 宽松的解码，单元的头损坏时记录到 warnings 中并跳过这个单元，而不是整个解码失败
+pub fn try_decode(path: impl AsRef<Path>) -> anyhow::Result<Map> {
+    decode_with_options(path, &DecodeOptions::default().skip_bad_units(true))
+}
+
+/// 解码地图并记录每个单元的大小和耗时
+pub fn decode_with_metrics(path: impl AsRef<Path>) -> anyhow::Result<(Map, DecodeMetrics)> {
+    let mut file = ParserContext::new(load_mapfile(path.as_ref())?, DecodeOptions::default())?;
+    file.metrics = Some(DecodeMetrics::default());
+    let map = decode_context(&mut file, None)?;
+    Ok((map, file.metrics.unwrap_or_default()))
+}
+
+/// decode_reuse 在多次调用之间复用的缓冲区
+#[derive(Debug, Default)]
+pub struct Scratch {
+    /// 整个地图文件的内容
+    file: Vec<u8>,
+    /// GEPJ 单元修复之前的数据
+    unit: Vec<u8>,
+}
+
+impl Scratch {
+    pub fn new() -> Scratch {
+        Scratch::default()
+    }
+}
+
+/// 和 decode 一样，但是文件内容和单元的临时数据都放在 scratch 中，
+/// 循环解码大量地图时复用同一个 scratch 可以省掉这部分内存分配。
+/// gzip 压缩的文件解压时还是会分配新的缓冲区
+pub fn decode_reuse(path: impl AsRef<Path>, scratch: &mut Scratch) -> anyhow::Result<Map> {
+    scratch.file.clear();
+    File::open(path)?.read_to_end(&mut scratch.file)?;
+    if scratch.file.starts_with(&GZIP_MAGIC) {
+        return decode_from_bytes(&scratch.file);
+    }
+
+    let bytes = Cursor::new(scratch.file.as_slice());
+    let mut file = ParserContext::new(bytes, DecodeOptions::default())?;
+    file.scratch = mem::take(&mut scratch.unit);
+    let map = decode_context(&mut file, None);
+    scratch.unit = mem::take(&mut file.scratch);
+    map
+}
+
+/// 读到让 predicate 返回 true 的单元就停止，不再读取后面的单元，
+/// 适合只需要先显示一部分单元的预览。提前停止时返回的 Map 的 partial 为 true
+pub fn decode_until(
+    path: impl AsRef<Path>,
+    predicate: impl Fn(&Unit) -> bool,
+) -> anyhow::Result<Map> {
+    let bytes = load_mapfile(path.as_ref())?;
+    decode_cursor(bytes, DecodeOptions::default(), Some(&predicate))
+}
+
+/// 从内存中的字节解码地图
+pub fn decode_from_bytes(bytes: &[u8]) -> anyhow::Result<Map> {
+    decode_from_bytes_with_options(bytes, &DecodeOptions::default())
+}
+
+/// 按 options 从内存中的字节解码地图
+pub fn decode_from_bytes_with_options(
+    bytes: &[u8],
+    options: &DecodeOptions,
+) -> anyhow::Result<Map> {
+    let bytes = Cursor::new(unwrap_gzip(bytes.to_vec())?);
+    decode_cursor(bytes, options.clone(), None)
+}
+
+/// 检查读完一段之后的位置和按字段算出来的是否一致，读错字节数会让后面的解析全部错位。
+/// strict 模式下直接报错，否则记录警告
+fn check_position<R: Read + Seek>(
+    file: &mut ParserContext<R>,
+    section: &str,
+    actual: u64,
+    expected: u64,
+) -> anyhow::Result<()> {
+    if actual == expected {
+        return Ok(());
+    }
+    file.warn_or_fail(anyhow::anyhow!(
+        "Parser drift after {}: at offset {}, expected {}",
+        section,
+        actual,
+        expected
+    ))
+}
+
+fn decode_cursor<B: AsRef<[u8]>>(
+    bytes: Cursor<B>,
+    options: DecodeOptions,
+    until: Option<&dyn Fn(&Unit) -> bool>,
+) -> anyhow::Result<Map> {
+    decode_context(&mut ParserContext::new(bytes, options)?, until)
+}
+
+/// 按顺序读取文件头、遮罩和单元，读取过程中的警告和字节区间都收集在 file 中
+fn decode_context<R: Read + Seek>(
+    file: &mut ParserContext<R>,
+    until: Option<&dyn Fn(&Unit) -> bool>,
+) -> anyhow::Result<Map> {
+    let header = read_header(file)?;
+    let header_end = header.header_size as u64 + header.map_index_list.len() as u64 * 4;
+    let position = file.position()?;
+    check_position(file, "header", position, header_end)?;
+    file.layout.push(Section::Header, 0..position);
+
+    let (mask_table_flag, masks) = read_mask(file)?;
+    let table_end = file
+        .layout
+        .ranges
+        .iter()
+        .find(|(s, _)| *s == Section::MaskTable)
+        .map(|(_, range)| range.end);
+    if let Some(end) = table_end {
+        let expected = header_end + 8 + masks.len() as u64 * 4;
+        check_position(file, "mask table", end, expected)?;
+    }
+    for mask in masks.iter().filter(|mask| !mask.fits_in(header.width, header.height)) {
+        file.warn_or_fail(anyhow::anyhow!(
+            "Mask {} at ({}, {}) with size {}x{} exceeds the {}x{} map",
+            mask.index(),
+            mask.x(),
+            mask.y(),
+            mask.width(),
+            mask.height(),
+            header.width,
+            header.height
+        ))?;
+    }
+    let (uints, partial) = read_unit(&header, file, until)?;
+
+    if file.options.strict {
+        let gaps = file.layout.gaps(file.len);
+        if !gaps.is_empty() {
+            return Err(anyhow::anyhow!("Unparsed bytes in map file: {:?}", gaps));
+        }
+    }
+
+    let raw_gaps = if file.options.preserve_raw {
+        read_raw_gaps(file, &masks, &uints)?
+    } else {
+        vec![]
+    };
 
     let map = Map {
         map_header: header,
         masks,
+        mask_table_flag,
         units: uints,
+        layout: mem::take(&mut file.layout),
+        warnings: mem::take(&mut file.warnings),
+        partial,
+        raw_gaps,
     };
     Ok(map)
 }
 
+/// 读取文件头、遮罩表和已经保留了原始字节的单元、遮罩都没有覆盖到的字节
+fn read_raw_gaps<R: Read + Seek>(
+    file: &mut ParserContext<R>,
+    masks: &[Mask],
+    units: &[Unit],
+) -> anyhow::Result<Vec<RawSpan>> {
+    let mut covered = Layout::default();
+    for (section, range) in file.layout.ranges.iter() {
+        if matches!(section, Section::Header | Section::MaskTable) {
+            covered.push(*section, range.clone());
+        }
+    }
+    for mask in masks {
+        if let Some(raw) = &mask.raw {
+            covered.push(Section::Mask(mask.index()), raw.range());
+        }
+    }
+    for unit in units {
+        if let Some(raw) = &unit.raw {
+            covered.push(Section::Unit(unit.slot), raw.range());
+        }
+    }
+
+    let mut gaps = vec![];
+    for gap in covered.gaps(file.len) {
+        file.seek_to(gap.end, "Gap")?;
+        gaps.push(file.raw_span(gap.start)?);
+    }
+    Ok(gaps)
+}
+
+/// 直接从 `bytes::Bytes` 解码，不复制数据 (gzip 压缩的数据还是要解压到新的缓冲区)
+#[cfg(feature = "bytes")]
+pub fn decode_from_bytes_ref(bytes: bytes::Bytes) -> anyhow::Result<Map> {
+    if bytes.starts_with(&GZIP_MAGIC) {
+        return decode_from_bytes(&bytes);
+    }
+    decode_cursor(Cursor::new(bytes), DecodeOptions::default(), None)
+}
+
+/// 从 zip 压缩包中按名字找到地图文件并解码，不会解压到磁盘上。
+/// zip 中的条目不能 seek，所以会先把这一个条目解压到内存中
+#[cfg(feature = "zip")]
+pub fn decode_from_archive(
+    archive_path: impl AsRef<Path>,
+    entry_name: &str,
+) -> anyhow::Result<Map> {
+    let reader = BufReader::new(File::open(archive_path.as_ref())?);
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut entry = archive.by_name(entry_name).with_context(|| {
+        format!("No entry {} in {}", entry_name, archive_path.as_ref().display())
+    })?;
+    let mut bytes = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut bytes)?;
+    let bytes = Cursor::new(unwrap_gzip(bytes)?);
+    decode_cursor(bytes, DecodeOptions::default(), None)
+}
+
+/// `let map: Map = path.try_into()?;`，等同于 decode
+impl TryFrom<&Path> for Map {
+    type Error = anyhow::Error;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        decode(path)
+    }
+}
+
+impl TryFrom<&[u8]> for Map {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        decode_from_bytes(bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use image::{imageops, RgbaImage};
 
     use super::*;
 
+    use crate::fixture;
+
+    fn context(bytes: Vec<u8>) -> ParserContext<Cursor<Vec<u8>>> {
+        ParserContext::new(Cursor::new(bytes), DecodeOptions::default()).unwrap()
+    }
+
+    fn header_bytes(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = b"0.1M".to_vec();
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn read_header_rejects_overflowing_dimensions() {
+        let mut bytes = context(header_bytes(u32::MAX, u32::MAX));
+        let err = read_header(&mut bytes).err().unwrap();
+        assert!(matches!(err.downcast_ref::<MapError>(), Some(MapError::Overflow(_))));
+    }
+
+    #[test]
+    fn read_unit_rejects_overflowing_prefix() {
+        let mut bytes = context(0x4000_0000u32.to_le_bytes().to_vec());
+        let err = read_unit_at(&mut bytes, 0, 0).err().unwrap();
+        assert!(matches!(err.downcast_ref::<MapError>(), Some(MapError::Overflow(_))));
+    }
+
+    #[test]
+    fn read_unit_rejects_inflated_size() {
+        let mut bytes = fixture::map_bytes();
+        let offset = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        let available = (bytes.len() - offset - 12) as u64;
+        bytes[offset + 8..offset + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let err = decode_from_bytes(&bytes).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<MapError>(),
+            Some(&MapError::UnitTooLarge {
+                slot: 0,
+                size: u32::MAX,
+                available
+            })
+        );
+    }
+
+    #[test]
+    fn read_header_truncates_short_index() {
+        // 640x240 算出来有 2 个单元，但文件里只存了 1 个，后面是遮罩表
+        let mut bytes = header_bytes(640, 240);
+        bytes.extend_from_slice(&100u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut cursor = context(bytes);
+        let header = read_header(&mut cursor).unwrap();
+        assert_eq!(header.header_size, 12);
+        assert_eq!(header.map_index_list, vec![100]);
+        assert_eq!(header.index_size, 1);
+        assert_eq!(cursor.position().unwrap(), 16);
+        assert_eq!(cursor.warnings.len(), 1);
+    }
+
+    #[test]
+    fn skip_bad_units() {
+        // 把单元的偏移改到文件末尾附近，读单元头时会越界
+        let mut bytes = fixture::map_bytes();
+        let end = (bytes.len() - 2) as u32;
+        bytes[12..16].copy_from_slice(&end.to_le_bytes());
+
+        assert!(decode_cursor(Cursor::new(bytes.clone()), DecodeOptions::default(), None).is_err());
+        let options = DecodeOptions::default().skip_bad_units(true);
+        let map = decode_cursor(Cursor::new(bytes), options, None).unwrap();
+        assert!(map.units.is_empty());
+        assert_eq!(map.masks.len(), 1);
+        assert_eq!(map.warnings.len(), 1);
+    }
+
+    #[test]
+    fn unit_decode_error_has_context() {
+        let unit = Unit {
+            slot: 7,
+            unit_flag: "GEPJ".to_string(),
+            size: 3,
+            unit_data: vec![0xFF, 0xD8, 0x00],
+            image: None,
+            valid: false,
+            mask_indices: vec![],
+            restart_markers: false,
+            raw: None,
+        };
+        let message = unit.decode().unwrap_err().to_string();
+        assert_eq!(message, "Failed to decode unit 7 (GEPJ, 3 bytes)");
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn decode_gzip() {
+        use std::io::Write;
+
+        let bytes = fixture::map_bytes();
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&bytes).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let map = decode_from_bytes(&gzipped).unwrap();
+        assert_eq!(map.units.len(), 1);
+        assert_eq!(map.masks.len(), 1);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn decode_from_bytes_ref_works() {
+        let map = decode_from_bytes_ref(bytes::Bytes::from(fixture::map_bytes())).unwrap();
+        assert_eq!(map.units.len(), 1);
+        assert_eq!(map.masks.len(), 1);
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn decode_from_archive_works() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("mh_map_archive.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        writer.start_file("maps/1001.map", Default::default()).unwrap();
+        writer.write_all(&fixture::map_bytes()).unwrap();
+        writer.finish().unwrap();
+
+        let map = decode_from_archive(&path, "maps/1001.map").unwrap();
+        assert_eq!(map.units.len(), 1);
+        assert_eq!(map.masks.len(), 1);
+        assert!(decode_from_archive(&path, "maps/1002.map").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn map_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Map>();
+        assert_send_sync::<MapHeader>();
+        assert_send_sync::<Unit>();
+        assert_send_sync::<Mask>();
+    }
+
+    #[test]
+    fn tile_order_col_major() {
+        let mut bytes = header_bytes(640, 480);
+        for offset in [100u32, 200, 300, 400] {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+        let mut header = read_header(&mut context(bytes)).unwrap();
+        assert_eq!(header.offset_of(0, 1), Some(200));
+
+        header.tile_order = TileOrder::ColMajor;
+        assert_eq!(header.offset_of(0, 1), Some(300));
+        assert_eq!(header.position_of(1), (1, 0));
+        assert_eq!(header.slot_of(1, 1), 3);
+    }
+
+    #[test]
+    fn peek_magic_works() {
+        let path = std::env::temp_dir().join("mh_map_peek_magic.map");
+        fs::write(&path, fixture::map_bytes()).unwrap();
+        assert_eq!(peek_magic(&path).unwrap(), Some(MapVersion::M1));
+
+        fs::write(&path, fixture::tile_jpeg(8, 8)).unwrap();
+        assert_eq!(peek_magic(&path).unwrap(), None);
+
+        fs::write(&path, b"0.1").unwrap();
+        assert_eq!(peek_magic(&path).unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn decode_index_works() {
+        let path = std::env::temp_dir().join("mh_map_decode_index.map");
+        fs::write(&path, fixture::map_bytes()).unwrap();
+        let header = decode_index(&path).unwrap();
+        assert_eq!((header.width, header.height), (fixture::WIDTH, fixture::HEIGHT));
+        assert_eq!(header.map_index_list.len(), 1);
+        let start = header.header_size as usize;
+        assert_eq!(header.index_bytes(), fixture::map_bytes()[start..start + 4]);
+        let map: Map = path.as_path().try_into().unwrap();
+        assert_eq!(map.units.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn map_from_parts() {
+        let mut unit = Unit::new("2GPJ", 0, fixture::tile_jpeg(8, 8));
+        unit.slot = 1;
+        let mask = Mask::new(0, 3, 4, 4, 1, MaskKind::Occlusion, vec![0xFF]).unwrap();
+        let map = Map::new(MapHeader::new(640, 240).unwrap(), vec![unit], vec![mask]);
+
+        let decoded = decode_from_bytes(&crate::encode::encode(&map).unwrap()).unwrap();
+        assert_eq!(decoded.units[0].slot, 1);
+        assert_eq!(decode_mask_bits(&decoded.masks[0]).unwrap(), vec![3, 3, 3, 3]);
+
+        let err = Mask::new(0, 0, 0, 4, 1, MaskKind::Rgb565, vec![0; 2]).err().unwrap();
+        assert!(matches!(err, MapError::MaskSizeMismatch { expected: 8, got: 2, .. }));
+    }
+
+    #[test]
+    fn header_accessors() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert_eq!(map.header().width, fixture::WIDTH);
+        assert_eq!(map.dimensions(), (fixture::WIDTH, fixture::HEIGHT));
+        assert_eq!(map.tile_grid(), (1, 1));
+    }
+
+    #[test]
+    fn coverage_counts_valid_slots() {
+        let mut map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert_eq!(map.coverage(), 1.0);
+
+        map.units[0].valid = false;
+        assert_eq!(map.coverage(), 0.0);
+
+        map.units.clear();
+        assert_eq!(map.coverage(), 0.0);
+    }
+
+    #[test]
+    fn decode_reuse_keeps_buffers() {
+        let path = std::env::temp_dir().join("mh_map_decode_reuse.map");
+        fs::write(&path, fixture::map_bytes()).unwrap();
+        let mut scratch = Scratch::new();
+        let first = decode_reuse(&path, &mut scratch).unwrap();
+        let capacity = scratch.file.capacity();
+        let second = decode_reuse(&path, &mut scratch).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(first.units[0].unit_data, second.units[0].unit_data);
+        assert_eq!(second.masks.len(), 1);
+        assert_eq!(scratch.file.capacity(), capacity);
+        assert_eq!(scratch.file, fixture::map_bytes());
+    }
+
+    #[test]
+    fn decode_zero_tile_map() {
+        let mut bytes = header_bytes(0, 0);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+
+        let map = decode_from_bytes(&bytes).unwrap();
+        assert!(map.is_empty());
+        assert!(map.units.is_empty());
+        let image = crate::render::render(&map).unwrap();
+        assert_eq!(image.dimensions(), (0, 0));
+    }
+
+    #[test]
+    fn memory_footprint_counts_unit_data() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let data = map.units[0].unit_data.len() + map.masks[0].data.len();
+        assert!(map.memory_footprint() > data);
+    }
+
+    #[test]
+    fn decode_until_stops_early() {
+        let mut builder = crate::builder::MapBuilder::with_dimensions(640, 240);
+        builder
+            .set_tile(0, 0, fixture::tile_jpeg(320, 240))
+            .set_tile(0, 1, fixture::tile_jpeg(320, 240));
+        let bytes = crate::encode::encode(&builder.build().unwrap()).unwrap();
+        let path = std::env::temp_dir().join("mh_map_decode_until.map");
+        fs::write(&path, bytes).unwrap();
+
+        let map = decode_until(&path, |unit| unit.slot == 0).unwrap();
+        assert!(map.partial);
+        assert_eq!(map.units.len(), 1);
+
+        let map = decode_until(&path, |unit| unit.slot == 1).unwrap();
+        assert!(!map.partial);
+        assert_eq!(map.units.len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn decode_unit_without_prefix() {
+        // 去掉单元前面的前缀，索引表里的偏移正好指向类型标识
+        let mut bytes = fixture::map_bytes();
+        let flag = bytes.windows(4).position(|w| w == b"2GPJ").unwrap();
+        bytes.drain(flag - 4..flag);
+
+        let options = DecodeOptions::default().strict(true);
+        let map = decode_cursor(Cursor::new(bytes), options, None).unwrap();
+        let expected = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert_eq!(map.units.len(), 1);
+        assert_eq!(map.units[0].unit_flag, "2GPJ");
+        assert!(map.units[0].mask_indices.is_empty());
+        assert_eq!(map.units[0].unit_data, expected.units[0].unit_data);
+    }
+
+    #[test]
+    fn decode_fixture() {
+        let options = DecodeOptions::default().strict(true);
+        let map = decode_cursor(Cursor::new(fixture::map_bytes()), options, None).unwrap();
+        assert_eq!(map.map_header.width, fixture::WIDTH);
+        assert_eq!(map.map_header.height, fixture::HEIGHT);
+        assert_eq!(map.units.len(), 1);
+        assert_eq!(map.units[0].unit_flag, "2GPJ");
+        assert_eq!(map.masks.len(), 1);
+
+        let (x, y, width, height) = fixture::MASK;
+        let mask = &map.masks[0];
+        assert_eq!((mask.x(), mask.y(), mask.width(), mask.height()), (x, y, width, height));
+        assert!(decode_mask_bits(mask).unwrap().iter().all(|bit| *bit == 3));
+
+        let (first, grid) = map.mask_grids().next().unwrap().unwrap();
+        assert_eq!(first.index(), 0);
+        assert_eq!(grid.get(0, 0), Some(MaskCell::Full));
+    }
+
+    #[test]
+    fn bounding_tiles_works() {
+        let mut bytes = header_bytes(700, 500);
+        for _ in 0..9 {
+            bytes.extend_from_slice(&1000u32.to_le_bytes());
+        }
+        let header = read_header(&mut context(bytes)).unwrap();
+
+        // 从第一个单元中间开始，跨过右边和下边的单元
+        assert_eq!(header.bounding_tiles(300, 200, 30, 50), vec![0, 1, 3, 4]);
+        // 超出地图的部分被忽略
+        assert_eq!(header.bounding_tiles(650, 490, 1000, 1000), vec![8]);
+        assert!(header.bounding_tiles(700, 0, 10, 10).is_empty());
+        assert!(header.bounding_tiles(0, 0, 0, 10).is_empty());
+    }
+
+    #[test]
+    fn tile_offsets_sorted() {
+        let mut bytes = header_bytes(640, 240);
+        bytes.extend_from_slice(&300u32.to_le_bytes());
+        bytes.extend_from_slice(&100u32.to_le_bytes());
+        let header = read_header(&mut context(bytes)).unwrap();
+
+        assert_eq!(header.tile_offsets(), vec![100, 300]);
+        assert_eq!(header.tile_offsets_with_slots(), vec![(1, 100), (0, 300)]);
+    }
+
+    #[test]
+    fn fix_2gpj_strips_padding() {
+        let mut unit = Unit {
+            slot: 0,
+            unit_flag: "2GPJ".to_string(),
+            size: 6,
+            unit_data: vec![0, 0, 0xFF, 0xD8, 0xFF, 0xD9],
+            image: None,
+            valid: false,
+            mask_indices: vec![],
+            restart_markers: false,
+            raw: None,
+        };
+        let mut warnings = vec![];
+        fix_2gpj(&mut unit, 0, &mut warnings);
+        assert_eq!(unit.unit_data, vec![0xFF, 0xD8, 0xFF, 0xD9]);
+        assert!(unit.is_valid_jpeg());
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn read_png_unit() {
+        let png = fixture::tile_png(4, 4);
+        let mut bytes = 0u32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"GNP ");
+        bytes.extend_from_slice(&(png.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&png);
+
+        let unit = read_unit_at(&mut context(bytes), 0, 0).unwrap().unwrap();
+        assert_eq!(unit.unit_data, png);
+        assert_eq!(unit.load_image().unwrap().width(), 4);
+    }
+
+    #[test]
+    fn units_and_masks_keep_file_order() {
+        let mut builder = crate::builder::MapBuilder::with_dimensions(640, 480);
+        for (row, col) in [(1, 1), (0, 1), (1, 0), (0, 0)] {
+            builder.set_tile(row, col, fixture::tile_jpeg(8, 8));
+        }
+        for x in [30, 10, 20] {
+            builder.add_mask(x, 0, RgbaImage::from_pixel(4, 2, image::Rgba([0, 0, 0, 255])));
+        }
+        let bytes = crate::encode::encode(&builder.build().unwrap()).unwrap();
+        let map = decode_from_bytes(&bytes).unwrap();
+
+        let slots = map.units.iter().map(|unit| unit.slot).collect::<Vec<_>>();
+        assert_eq!(slots, vec![0, 1, 2, 3]);
+        let indices = map.masks.iter().map(|mask| mask.index()).collect::<Vec<_>>();
+        assert_eq!(indices, vec![0, 1, 2]);
+        let xs = map.masks.iter().map(|mask| mask.x()).collect::<Vec<_>>();
+        assert_eq!(xs, vec![30, 10, 20]);
+    }
+
+    #[test]
+    fn read_header_skips_extra_fields() {
+        let mut bytes = header_bytes(640, 240);
+        bytes.extend_from_slice(&[0xAA; 4]);
+        bytes.extend_from_slice(&24u32.to_le_bytes());
+        bytes.extend_from_slice(&24u32.to_le_bytes());
+
+        let mut file = context(bytes);
+        file.seek(SeekFrom::Start(4)).unwrap();
+        let header = read_header_fields(&mut file, *b"0.1M", MapVersion::M1, 4).unwrap();
+        assert_eq!(header.header_size, 16);
+        assert_eq!(header.map_index_list, vec![24, 24]);
+        assert_eq!(MapVersion::M1.header_size(), 12);
+    }
+
+    #[test]
+    fn read_header_with_u16_dimensions() {
+        let mut bytes = b"XPAM".to_vec();
+        bytes.extend_from_slice(&640u16.to_le_bytes());
+        bytes.extend_from_slice(&240u16.to_le_bytes());
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+
+        let header = read_header(&mut context(bytes)).unwrap();
+        assert_eq!(header.version, MapVersion::MapX);
+        assert_eq!(header.dimension_format, DimensionFormat::U16);
+        assert_eq!((header.width, header.height), (640, 240));
+        assert_eq!(header.header_size, 8);
+        assert_eq!(header.map_index_list, vec![16, 16]);
+    }
+
+    #[test]
+    fn unit_dimensions_without_decoding() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert_eq!(map.units[0].dimensions().unwrap(), (fixture::WIDTH, fixture::HEIGHT));
+
+        let png = Unit::new("PNG ", 0, fixture::tile_png(5, 3));
+        assert_eq!(png.dimensions().unwrap(), (5, 3));
+        assert!(Unit::new("GEPJ", 2, vec![0, 1]).dimensions().is_err());
+    }
+
+    #[test]
+    fn read_unit_keeps_mask_indices() {
+        let jpeg = fixture::tile_jpeg(8, 8);
+        let mut bytes = vec![];
+        for value in [2u32, 0, 3] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(b"2GPJ");
+        bytes.extend_from_slice(&(jpeg.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&jpeg);
+
+        let unit = read_unit_at(&mut context(bytes), 0, 0).unwrap().unwrap();
+        assert_eq!(unit.mask_indices, vec![0, 3]);
+        assert_eq!(unit.unit_data, jpeg);
+    }
+
+    #[test]
+    fn read_unit_without_prefix() {
+        let jpeg = fixture::tile_jpeg(4, 4);
+        let mut bytes = b"2GPJ".to_vec();
+        bytes.extend_from_slice(&(jpeg.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&jpeg);
+
+        let unit = read_unit_at(&mut context(bytes), 0, 0).unwrap().unwrap();
+        assert_eq!(unit.unit_flag, "2GPJ");
+        assert_eq!(unit.unit_data, jpeg);
+    }
+
     #[test]
     fn it_works() {
-        let filename = "1003.map";
-        let mut bytes = load_mapfile(filename).unwrap();
+        let mut bytes = context(fixture::map_bytes());
         let header = read_header(&mut bytes).unwrap();
-        let masks = read_mask(&mut bytes).unwrap();
-        let uints = read_unit(&header, &mut bytes).unwrap();
+        let (_, masks) = read_mask(&mut bytes).unwrap();
+        let (uints, _) = read_unit(&header, &mut bytes, None).unwrap();
+        assert_eq!(masks.len(), 1);
 
         let mut bk = RgbaImage::new(header.width, header.height);
         for i in 0..header.rows {
             for j in 0..header.cols {
                 let index = i * header.cols + j;
                 let unit = &uints[index as usize];
-                let unit_image = image::load_from_memory(&unit.unit_data).unwrap();
+                let unit_image = unit.decode().unwrap();
                 imageops::overlay(&mut bk, &unit_image, (j * 320) as i64, (i * 240) as i64);
             }
         }
-        bk.save(format!("{}.jpg", 1003)).unwrap();
+        assert_eq!(bk.dimensions(), (fixture::WIDTH, fixture::HEIGHT));
+        assert_eq!(bk.get_pixel(0, 0)[3], 255);
     }
 }
@@ -0,0 +1,150 @@
+//! 解析时在各个读取函数之间共用的上下文
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::error::MapError;
+use crate::layout::Layout;
+use crate::new_map::{DecodeMetrics, DecodeOptions, RawSpan};
+
+/// 解码出的单元和遮罩总共最多占用文件长度多少倍的内存。
+/// LZO 最多展开 256 倍，正常的文件远到不了这个值
+const ALLOCATION_FACTOR: u64 = 512;
+
+/// 包装读取的文件，记住文件的总长度和解码选项，并收集解析过程中产生的信息。
+/// 各个读取函数都只接收这一个参数，加新的选项时不用再改一串函数签名
+pub(crate) struct ParserContext<R> {
+    pub(crate) reader: R,
+    /// 文件总长度，创建时读取一次
+    pub(crate) len: u64,
+    pub(crate) options: DecodeOptions,
+    /// 解析时读取过的字节区间
+    pub(crate) layout: Layout,
+    /// 不影响继续解码的问题
+    pub(crate) warnings: Vec<String>,
+    /// 为 Some 时记录每个单元的大小和耗时
+    pub(crate) metrics: Option<DecodeMetrics>,
+    /// 读取单元时复用的临时缓冲区
+    pub(crate) scratch: Vec<u8>,
+    /// 到目前为止解码出的单元和遮罩的字节数
+    allocated: u64,
+}
+
+impl<R: Read + Seek> ParserContext<R> {
+    /// 创建时用 seek 到结尾的方式取得文件长度，不改变当前位置
+    pub(crate) fn new(mut reader: R, options: DecodeOptions) -> io::Result<ParserContext<R>> {
+        let position = reader.stream_position()?;
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(position))?;
+        Ok(ParserContext {
+            reader,
+            len,
+            options,
+            layout: Layout::default(),
+            warnings: vec![],
+            metrics: None,
+            scratch: vec![],
+            allocated: 0,
+        })
+    }
+
+    pub(crate) fn position(&mut self) -> io::Result<u64> {
+        self.reader.stream_position()
+    }
+
+    /// 从当前位置到文件结尾还剩多少字节
+    pub(crate) fn remaining(&mut self) -> io::Result<u64> {
+        Ok(self.len.saturating_sub(self.position()?))
+    }
+
+    /// 跳到 offset 处，offset 超出文件时返回错误，what 用于错误信息
+    pub(crate) fn seek_to(&mut self, offset: u64, what: &str) -> anyhow::Result<()> {
+        if offset > self.len {
+            return Err(anyhow::anyhow!(
+                "{} offset {} is beyond the end of the file ({} bytes)",
+                what,
+                offset,
+                self.len
+            ));
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+
+    /// 记录解码出了 bytes 字节的单元或遮罩数据，总量超过文件长度的 ALLOCATION_FACTOR 倍时返回错误。
+    /// 每一项的大小在读取时已经检查过，这里防的是很多项指向同一段数据，每一项都再解出一份
+    pub(crate) fn charge(&mut self, bytes: u64) -> crate::Result<()> {
+        let limit = self.len.saturating_mul(ALLOCATION_FACTOR);
+        let requested = self.allocated.saturating_add(bytes);
+        if requested > limit {
+            return Err(MapError::AllocationLimit { requested, limit });
+        }
+        self.allocated = requested;
+        Ok(())
+    }
+
+    /// 读取 start 到当前位置之间的原始字节，读完后位置不变
+    pub(crate) fn raw_span(&mut self, start: u64) -> anyhow::Result<RawSpan> {
+        let end = self.position()?;
+        self.seek_to(start, "Raw span")?;
+        let mut bytes = vec![0u8; (end - start) as usize];
+        self.read_exact(&mut bytes)?;
+        Ok(RawSpan {
+            offset: start,
+            bytes,
+        })
+    }
+
+    /// 不符合预期但还能继续解码的问题，strict 模式下返回错误，否则记录为警告
+    pub(crate) fn warn_or_fail<E>(&mut self, error: E) -> anyhow::Result<()>
+    where
+        E: Into<anyhow::Error> + std::fmt::Display,
+    {
+        if self.options.strict {
+            return Err(error.into());
+        }
+        self.warnings.push(error.to_string());
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ParserContext<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for ParserContext<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn context_tracks_len() {
+        let mut cursor = Cursor::new(vec![0u8; 10]);
+        cursor.set_position(4);
+        let mut context = ParserContext::new(cursor, DecodeOptions::default()).unwrap();
+        assert_eq!(context.len, 10);
+        assert_eq!(context.remaining().unwrap(), 6);
+        assert!(context.seek_to(10, "Unit").is_ok());
+        assert!(context.seek_to(11, "Unit").is_err());
+    }
+
+    #[test]
+    fn warn_or_fail_follows_strict() {
+        let cursor = Cursor::new(Vec::<u8>::new());
+        let mut context = ParserContext::new(cursor, DecodeOptions::default()).unwrap();
+        assert!(context.warn_or_fail(anyhow::anyhow!("drift")).is_ok());
+        assert_eq!(context.warnings, vec!["drift".to_string()]);
+
+        context.options.strict = true;
+        assert!(context.warn_or_fail(anyhow::anyhow!("drift")).is_err());
+        assert_eq!(context.warnings.len(), 1);
+    }
+}
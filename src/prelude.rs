@@ -0,0 +1,6 @@
+//! 常用类型和函数，`use mh_map::prelude::*;` 即可使用
+
+pub use crate::decoder::TileDecoder;
+pub use crate::error::MapError;
+pub use crate::new_map::{decode, decode_with_options, DecodeOptions, Map, MapHeader, Mask, Unit};
+pub use crate::render::render;
@@ -0,0 +1,752 @@
+use std::{borrow::Cow, io::Write};
+
+use image::{
+    codecs::jpeg::JpegEncoder, imageops, imageops::FilterType, ColorType, DynamicImage,
+    ImageBuffer, Pixel, Rgba, RgbImage, RgbaImage,
+};
+
+use crate::buffer_utils;
+use crate::decoder::{ImageDecoder, TileDecoder};
+use crate::error::MapError;
+use crate::mask::decode_mask_image;
+use crate::new_map::{Map, Unit};
+use crate::validate::MAX_DIMENSION;
+
+/// 单元图片的宽度
+pub const TILE_WIDTH: u32 = 320;
+/// 单元图片的高度
+pub const TILE_HEIGHT: u32 = 240;
+
+/// 单元解码失败时怎么办
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnTileError {
+    /// 跳过这个单元，对应的区域保持空白
+    Skip,
+    /// 用指定颜色填充这个单元的区域
+    Placeholder(Rgba<u8>),
+    /// 直接返回错误
+    #[default]
+    Abort,
+}
+
+/// 渲染选项
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions {
+    pub on_error: OnTileError,
+    /// 先用这个颜色填满整张图再拼单元，没有单元的地方就是这个颜色，默认透明
+    pub background: Rgba<u8>,
+    /// 调试用，在单元的边界上画 1 像素的线，检查单元和索引表是否对齐
+    pub draw_grid: bool,
+    /// 网格线和序号的颜色，默认红色
+    pub grid_color: Rgba<u8>,
+    /// 调试用，在每个单元的左上角写上它的序号 (slot)
+    pub draw_slot_index: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            on_error: OnTileError::default(),
+            background: Rgba([0, 0, 0, 0]),
+            draw_grid: false,
+            grid_color: Rgba([255, 0, 0, 255]),
+            draw_slot_index: false,
+        }
+    }
+}
+
+/// 渲染结果
+pub struct Rendered<I> {
+    pub image: I,
+    /// 解码失败 (被跳过或者用颜色填充) 的单元位置和错误信息
+    pub failed_slots: Vec<(usize, String)>,
+}
+
+/// 第 slot 个单元在地图中的区域 (x, y, width, height)，边上的单元会被裁剪到地图范围内
+pub(crate) fn tile_rect(map: &Map, slot: usize) -> (u32, u32, u32, u32) {
+    let header = &map.map_header;
+    let (row, col) = header.position_of(slot);
+    let x = col * TILE_WIDTH;
+    let y = row * TILE_HEIGHT;
+    let width = TILE_WIDTH.min(header.width.saturating_sub(x));
+    let height = TILE_HEIGHT.min(header.height.saturating_sub(y));
+    (x, y, width, height)
+}
+
+/// 获取单元图片，已经解码过的直接借用，否则用 decoder 解码
+pub(crate) fn load_tile<'a>(
+    unit: &'a Unit,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<Cow<'a, DynamicImage>> {
+    match &unit.image {
+        Some(image) => Ok(Cow::Borrowed(image)),
+        None => Ok(Cow::Owned(DynamicImage::ImageRgba8(unit.decode_with(decoder)?))),
+    }
+}
+
+/// 把所有单元按行优先的顺序拼到一起，convert 负责把单元图片转成目标像素格式，
+/// pixel 负责把背景色和占位颜色转成目标像素格式
+fn render_tiles<P>(
+    map: &Map,
+    options: &RenderOptions,
+    decoder: &dyn TileDecoder,
+    convert: impl Fn(&DynamicImage) -> ImageBuffer<P, Vec<u8>>,
+    pixel: impl Fn(Rgba<u8>) -> P,
+) -> anyhow::Result<Rendered<ImageBuffer<P, Vec<u8>>>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let header = &map.map_header;
+    let mut canvas =
+        ImageBuffer::from_pixel(header.width, header.height, pixel(options.background));
+    let mut failed_slots = vec![];
+
+    for unit in map.units.iter() {
+        let (x, y, width, height) = tile_rect(map, unit.slot);
+        let image = match load_tile(unit, decoder) {
+            Ok(image) => image,
+            Err(e) => match options.on_error {
+                OnTileError::Abort => return Err(e),
+                OnTileError::Skip => {
+                    failed_slots.push((unit.slot, e.to_string()));
+                    continue;
+                }
+                OnTileError::Placeholder(color) => {
+                    failed_slots.push((unit.slot, e.to_string()));
+                    let color = pixel(color);
+                    for py in y..y + height {
+                        for px in x..x + width {
+                            canvas.put_pixel(px, py, color);
+                        }
+                    }
+                    continue;
+                }
+            },
+        };
+        let tile = convert(&*image);
+        imageops::replace(&mut canvas, &tile, x as i64, y as i64);
+    }
+    draw_debug_overlay(&mut canvas, map, options, pixel(options.grid_color));
+    Ok(Rendered {
+        image: canvas,
+        failed_slots,
+    })
+}
+
+/// 3x5 的数字点阵，每行低 3 位从左到右
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// 数字点阵放大的倍数
+const DIGIT_SCALE: u32 = 2;
+
+/// 在 (x, y) 处写一个十进制数，超出图片的部分不画
+fn draw_number<P>(canvas: &mut ImageBuffer<P, Vec<u8>>, x: u32, y: u32, n: usize, color: P)
+where
+    P: Pixel<Subpixel = u8>,
+{
+    for (i, digit) in n.to_string().bytes().enumerate() {
+        let glyph = &DIGITS[(digit - b'0') as usize];
+        let left = x + i as u32 * 4 * DIGIT_SCALE;
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..3u32 {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..DIGIT_SCALE {
+                    for dx in 0..DIGIT_SCALE {
+                        let px = left + col * DIGIT_SCALE + dx;
+                        let py = y + row as u32 * DIGIT_SCALE + dy;
+                        if px < canvas.width() && py < canvas.height() {
+                            canvas.put_pixel(px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 按选项画单元边界和序号，有单元和没单元的位置都画，方便发现缺失的单元
+fn draw_debug_overlay<P>(
+    canvas: &mut ImageBuffer<P, Vec<u8>>,
+    map: &Map,
+    options: &RenderOptions,
+    color: P,
+) where
+    P: Pixel<Subpixel = u8>,
+{
+    let header = &map.map_header;
+    if options.draw_grid {
+        for col in 0..header.cols {
+            let x = col * TILE_WIDTH;
+            for y in 0..canvas.height() {
+                canvas.put_pixel(x, y, color);
+            }
+        }
+        for row in 0..header.rows {
+            let y = row * TILE_HEIGHT;
+            for x in 0..canvas.width() {
+                canvas.put_pixel(x, y, color);
+            }
+        }
+    }
+    if options.draw_slot_index {
+        for row in 0..header.rows {
+            for col in 0..header.cols {
+                let slot = header.slot_of(row, col);
+                draw_number(canvas, col * TILE_WIDTH + 4, row * TILE_HEIGHT + 4, slot, color);
+            }
+        }
+    }
+}
+
+/// 按选项渲染，可以容忍解码失败的单元
+pub fn render_with_options(map: &Map, options: &RenderOptions) -> anyhow::Result<Rendered<RgbaImage>> {
+    render_with_decoder(map, options, &ImageDecoder)
+}
+
+/// 和 render_with_options 一样，但是用指定的解码器解码单元，
+/// 开启 `eager-images` 时已经解码过的单元不会再解码
+pub fn render_with_decoder(
+    map: &Map,
+    options: &RenderOptions,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<Rendered<RgbaImage>> {
+    render_tiles(map, options, decoder, |tile| tile.to_rgba8(), |color| color)
+}
+
+/// 把所有单元拼成完整的地图
+pub fn render(map: &Map) -> anyhow::Result<RgbaImage> {
+    Ok(render_with_options(map, &RenderOptions::default())?.image)
+}
+
+/// `let image: RgbaImage = (&map).try_into()?;`，等同于 render
+impl TryFrom<&Map> for RgbaImage {
+    type Error = anyhow::Error;
+
+    fn try_from(map: &Map) -> Result<Self, Self::Error> {
+        render(map)
+    }
+}
+
+/// 和 render 一样，但是不带透明通道，单元都是不透明的 JPEG，这样可以省下 1/4 的内存
+pub fn render_rgb(map: &Map) -> anyhow::Result<RgbImage> {
+    let rendered = render_tiles(
+        map,
+        &RenderOptions::default(),
+        &ImageDecoder,
+        |tile| tile.to_rgb8(),
+        |color| color.to_rgb(),
+    )?;
+    Ok(rendered.image)
+}
+
+/// 渲染成 RGB 并编码成 JPEG，quality 的范围是 1-100，适合直接返回给网页的缩略图接口
+pub fn render_to_jpeg_bytes(map: &Map, quality: u8) -> anyhow::Result<Vec<u8>> {
+    if !(1..=100).contains(&quality) {
+        return Err(anyhow::anyhow!("JPEG quality must be 1-100, got {}", quality));
+    }
+    let image = render_rgb(map)?;
+    let mut bytes = vec![];
+    JpegEncoder::new_with_quality(&mut bytes, quality).encode(
+        &image,
+        image.width(),
+        image.height(),
+        ColorType::Rgb8,
+    )?;
+    Ok(bytes)
+}
+
+/// 把单元图片复制到一条单元行高的 RGBA 缓冲区中，width 是地图的宽度，
+/// x 是单元左边在地图中的位置，超出地图的部分不复制
+fn copy_tile_to_strip(strip: &mut [u8], width: usize, tile: &RgbaImage, x: usize) {
+    if width == 0 {
+        return;
+    }
+    let strip_height = strip.len() / (width * 4);
+    let tile_width = tile.width() as usize;
+    let copy_width = tile_width.min(width.saturating_sub(x));
+    let copy_height = (tile.height() as usize).min(strip_height);
+    for y in 0..copy_height {
+        let src = &tile.as_raw()[y * tile_width * 4..][..copy_width * 4];
+        let dst = (y * width + x) * 4;
+        strip[dst..dst + copy_width * 4].copy_from_slice(src);
+    }
+}
+
+/// 按单元行分组的单元，下标是单元所在的行
+pub(crate) fn units_by_row(map: &Map) -> Vec<Vec<&Unit>> {
+    let header = &map.map_header;
+    let mut rows: Vec<Vec<&Unit>> = vec![vec![]; header.rows as usize];
+    for unit in map.units.iter() {
+        let (row, _) = header.position_of(unit.slot);
+        if let Some(row) = rows.get_mut(row as usize) {
+            row.push(unit);
+        }
+    }
+    rows
+}
+
+/// 把第 row 行的单元渲染到 strip 中 (RGBA，宽度是地图的宽度)，没有单元的地方是透明的，
+/// 返回这一行的高度，最后一行会被裁剪到地图范围内
+pub(crate) fn render_row_strip(
+    map: &Map,
+    row: u32,
+    units: &[&Unit],
+    decoder: &dyn TileDecoder,
+    strip: &mut Vec<u8>,
+) -> anyhow::Result<u32> {
+    let header = &map.map_header;
+    let width = header.width as usize;
+    let top = row * TILE_HEIGHT;
+    let strip_height = TILE_HEIGHT.min(header.height.saturating_sub(top));
+    strip.clear();
+    strip.resize(width * strip_height as usize * 4, 0);
+    draw_row_units(map, units, decoder, strip)?;
+    Ok(strip_height)
+}
+
+/// 把同一行的单元复制到这一行的 RGBA 缓冲区中
+fn draw_row_units(
+    map: &Map,
+    units: &[&Unit],
+    decoder: &dyn TileDecoder,
+    strip: &mut [u8],
+) -> anyhow::Result<()> {
+    let header = &map.map_header;
+    for unit in units {
+        let tile = load_tile(unit, decoder)?.to_rgba8();
+        let (_, col) = header.position_of(unit.slot);
+        copy_tile_to_strip(strip, header.width as usize, &tile, (col * TILE_WIDTH) as usize);
+    }
+    Ok(())
+}
+
+/// 按单元行一条一条地渲染并写成 PNG，内存中只有一行单元高的图片，不是整张地图，
+/// 用来导出 render 会占用太多内存的大地图。没有单元的地方是透明的。
+/// 宽或高为 0 时和 render 一样不算错误，但 PNG 没法表示空图片，所以什么也不写
+pub fn stream_render_png<W: Write>(map: &Map, writer: W) -> anyhow::Result<()> {
+    stream_render_png_with_decoder(map, writer, &ImageDecoder)
+}
+
+/// 和 stream_render_png 一样，但是用指定的解码器解码单元
+pub fn stream_render_png_with_decoder<W: Write>(
+    map: &Map,
+    writer: W,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<()> {
+    let header = &map.map_header;
+    if header.width == 0 || header.height == 0 {
+        return Ok(());
+    }
+    let mut encoder = png::Encoder::new(writer, header.width, header.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let mut stream = writer.stream_writer()?;
+
+    let mut strip = vec![];
+    for (row, units) in units_by_row(map).iter().enumerate() {
+        render_row_strip(map, row as u32, units, decoder, &mut strip)?;
+        stream.write_all(&strip)?;
+    }
+    stream.finish()?;
+    Ok(())
+}
+
+/// 多线程渲染，每个线程负责一整行单元，写入输出图片中互不重叠的区域，不需要加锁
+#[cfg(feature = "rayon")]
+pub fn render_parallel(map: &Map) -> anyhow::Result<RgbaImage> {
+    render_parallel_with_decoder(map, &ImageDecoder)
+}
+
+/// 和 render_parallel 一样，但是用指定的解码器解码单元，各个线程共用同一个解码器
+#[cfg(feature = "rayon")]
+pub fn render_parallel_with_decoder(
+    map: &Map,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<RgbaImage> {
+    use rayon::prelude::*;
+
+    let header = &map.map_header;
+    let width = header.width as usize;
+    let rows = units_by_row(map);
+
+    let mut buffer = vec![0u8; width * header.height as usize * 4];
+    let strip_len = width * TILE_HEIGHT as usize * 4;
+    if strip_len > 0 {
+        buffer
+            .par_chunks_mut(strip_len)
+            .zip(rows.par_iter())
+            .try_for_each(|(strip, units)| draw_row_units(map, units, decoder, strip))?;
+    }
+
+    RgbaImage::from_raw(header.width, header.height, buffer)
+        .ok_or_else(|| anyhow::anyhow!("Render buffer size mismatch"))
+}
+
+/// 每个单元先按整数倍缩放再拼接，适合用 FilterType::Nearest 做像素风格的放大
+pub fn render_scaled(map: &Map, factor: u32, filter: FilterType) -> anyhow::Result<RgbaImage> {
+    render_scaled_with_decoder(map, factor, filter, &ImageDecoder)
+}
+
+/// 和 render_scaled 一样，但是用指定的解码器解码单元
+pub fn render_scaled_with_decoder(
+    map: &Map,
+    factor: u32,
+    filter: FilterType,
+    decoder: &dyn TileDecoder,
+) -> anyhow::Result<RgbaImage> {
+    if factor == 0 {
+        return Err(anyhow::anyhow!("Scale factor must be at least 1"));
+    }
+    let header = &map.map_header;
+    let width = buffer_utils::checked_mul(header.width, factor, "scaled width")?;
+    let height = buffer_utils::checked_mul(header.height, factor, "scaled height")?;
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(anyhow::anyhow!(
+            "Scaled map {}x{} exceeds {}",
+            width,
+            height,
+            MAX_DIMENSION
+        ));
+    }
+
+    let mut canvas = RgbaImage::new(width, height);
+    for unit in map.units.iter() {
+        let (row, col) = header.position_of(unit.slot);
+        let tile = load_tile(unit, decoder)?.to_rgba8();
+        let tile = imageops::resize(&tile, tile.width() * factor, tile.height() * factor, filter);
+        imageops::replace(
+            &mut canvas,
+            &tile,
+            (col * TILE_WIDTH * factor) as i64,
+            (row * TILE_HEIGHT * factor) as i64,
+        );
+    }
+    Ok(canvas)
+}
+
+/// 把相邻的几张地图拼成一张世界地图，parts 中每一项是地图和它左上角在世界中的坐标，
+/// 重叠的部分后面的地图覆盖前面的，没有地图的地方是透明的
+pub fn stitch_maps(parts: &[(Map, u32, u32)]) -> anyhow::Result<RgbaImage> {
+    let (mut width, mut height) = (0u32, 0u32);
+    for (map, x, y) in parts {
+        let right = x
+            .checked_add(map.map_header.width)
+            .ok_or(MapError::Overflow("world width"))?;
+        let bottom = y
+            .checked_add(map.map_header.height)
+            .ok_or(MapError::Overflow("world height"))?;
+        width = width.max(right);
+        height = height.max(bottom);
+    }
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(anyhow::anyhow!(
+            "World map {}x{} exceeds {}",
+            width,
+            height,
+            MAX_DIMENSION
+        ));
+    }
+
+    let mut canvas = RgbaImage::new(width, height);
+    for (map, x, y) in parts {
+        imageops::replace(&mut canvas, &render(map)?, *x as i64, *y as i64);
+    }
+    Ok(canvas)
+}
+
+/// 拼接地图并把遮罩层画在上面，遮罩需要透明通道所以只有 RGBA 版本
+pub fn render_with_masks(map: &Map) -> anyhow::Result<RgbaImage> {
+    let mut canvas = render(map)?;
+    let layer = render_mask_layer(map)?;
+    imageops::overlay(&mut canvas, &layer, 0, 0);
+    Ok(canvas)
+}
+
+/// 把所有遮罩按文件偏移表中的顺序画到一张和地图同样大小的透明图片上，
+/// 后面的遮罩会覆盖在前面的遮罩之上。超出地图范围的部分会被裁掉
+pub fn render_mask_layer(map: &Map) -> anyhow::Result<RgbaImage> {
+    let (width, height) = (map.map_header.width, map.map_header.height);
+    let mut layer = RgbaImage::new(width, height);
+
+    let mut masks = map.masks.iter().collect::<Vec<_>>();
+    masks.sort_by_key(|mask| mask.index());
+
+    for mask in masks {
+        if mask.x() >= width || mask.y() >= height {
+            continue;
+        }
+        let image = decode_mask_image(mask)?;
+        let visible_width = mask.width().min(width - mask.x());
+        let visible_height = mask.height().min(height - mask.y());
+        let visible = imageops::crop_imm(&image, 0, 0, visible_width, visible_height).to_image();
+        imageops::overlay(&mut layer, &visible, mask.x() as i64, mask.y() as i64);
+    }
+    Ok(layer)
+}
+
+/// 单元像素的平均颜色，用于按地图的色调做界面主题
+///
+/// sample_rate 为 N 时只解码每 N 个单元中的一个，N 越大越快但越不准，为 1 时解码全部单元
+pub fn average_color(map: &Map, sample_rate: usize) -> anyhow::Result<Rgba<u8>> {
+    if sample_rate == 0 {
+        return Err(anyhow::anyhow!("Sample rate must be at least 1"));
+    }
+    let mut sums = [0u64; 4];
+    let mut count = 0u64;
+    for unit in map.units.iter().step_by(sample_rate) {
+        let tile = unit.load_image()?.to_rgba8();
+        for pixel in tile.pixels() {
+            for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+                *sum += channel as u64;
+            }
+        }
+        count += tile.width() as u64 * tile.height() as u64;
+    }
+    if count == 0 {
+        return Err(anyhow::anyhow!("Map has no tiles to sample"));
+    }
+    Ok(Rgba(sums.map(|sum| (sum / count) as u8)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::new_map::decode_from_bytes;
+
+    #[test]
+    fn background_fills_missing_tiles() {
+        let mut map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        map.units.clear();
+        let white = Rgba([255, 255, 255, 255]);
+        let options = RenderOptions {
+            background: white,
+            ..Default::default()
+        };
+        let rendered = render_with_options(&map, &options).unwrap();
+        assert!(rendered.image.pixels().all(|pixel| *pixel == white));
+    }
+
+    #[test]
+    fn on_tile_error_skips_or_fills_corrupt_tiles() {
+        let mut builder = crate::builder::MapBuilder::with_dimensions(640, 240);
+        builder.set_tile(0, 0, fixture::tile_jpeg(320, 240));
+        builder.set_tile(0, 1, fixture::tile_jpeg(320, 240));
+        let mut map = builder.build().unwrap();
+        let corrupt = map.units.iter_mut().find(|unit| unit.slot == 1).unwrap();
+        corrupt.unit_data = b"not an image".to_vec();
+        corrupt.image = None;
+
+        let with = |on_error| RenderOptions {
+            on_error,
+            ..Default::default()
+        };
+        assert!(render_with_options(&map, &with(OnTileError::Abort)).is_err());
+
+        let skipped = render_with_options(&map, &with(OnTileError::Skip)).unwrap();
+        assert_eq!(skipped.failed_slots.iter().map(|(slot, _)| *slot).collect::<Vec<_>>(), [1]);
+        assert_eq!(*skipped.image.get_pixel(400, 100), Rgba([0, 0, 0, 0]));
+        assert_eq!(skipped.image.get_pixel(100, 100)[3], 255);
+
+        let green = Rgba([0, 255, 0, 255]);
+        let filled = render_with_options(&map, &with(OnTileError::Placeholder(green))).unwrap();
+        assert_eq!(filled.failed_slots.iter().map(|(slot, _)| *slot).collect::<Vec<_>>(), [1]);
+        let tile = imageops::crop_imm(&filled.image, 320, 0, 320, 240).to_image();
+        assert!(tile.pixels().all(|pixel| *pixel == green));
+        assert_ne!(*filled.image.get_pixel(100, 100), green);
+    }
+
+    #[test]
+    fn draw_grid_and_slot_index() {
+        let mut map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        map.units.clear();
+        let options = RenderOptions {
+            draw_grid: true,
+            draw_slot_index: true,
+            ..Default::default()
+        };
+        let image = render_with_options(&map, &options).unwrap().image;
+        let red = options.grid_color;
+        assert_eq!(*image.get_pixel(0, 100), red);
+        assert_eq!(*image.get_pixel(100, 0), red);
+        assert_eq!(*image.get_pixel(100, 100), Rgba([0, 0, 0, 0]));
+        // 序号 0 的左上角
+        assert_eq!(*image.get_pixel(4, 4), red);
+        // 数字 0 中间是空的
+        assert_eq!(*image.get_pixel(6, 8), Rgba([0, 0, 0, 0]));
+
+        let plain = render_with_options(&map, &RenderOptions::default()).unwrap().image;
+        assert!(plain.pixels().all(|pixel| pixel[3] == 0));
+    }
+
+    #[test]
+    fn average_color_of_solid_tile() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let color = average_color(&map, 1).unwrap();
+        // 纯色的 JPEG 解码后会有一点误差
+        for (channel, expected) in color.0.iter().zip([200u8, 100, 50, 255]) {
+            assert!(channel.abs_diff(expected) <= 3, "{:?}", color);
+        }
+        assert!(average_color(&map, 0).is_err());
+    }
+
+    #[test]
+    fn render_to_jpeg_bytes_works() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let bytes = render_to_jpeg_bytes(&map, 80).unwrap();
+        let image = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(image.color(), ColorType::Rgb8);
+        assert_eq!((image.width(), image.height()), (fixture::WIDTH, fixture::HEIGHT));
+        assert!(render_to_jpeg_bytes(&map, 0).is_err());
+        assert!(render_to_jpeg_bytes(&map, 101).is_err());
+    }
+
+    #[test]
+    fn render_scaled_multiplies_size() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let scaled = render_scaled(&map, 3, FilterType::Nearest).unwrap();
+        assert_eq!(scaled.dimensions(), (fixture::WIDTH * 3, fixture::HEIGHT * 3));
+
+        let original = render(&map).unwrap();
+        for (x, y) in [(0, 0), (100, 50), (fixture::WIDTH - 1, fixture::HEIGHT - 1)] {
+            assert_eq!(scaled.get_pixel(x * 3 + 1, y * 3 + 2), original.get_pixel(x, y));
+        }
+    }
+
+    #[test]
+    fn render_scaled_rejects_bad_factors() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert!(render_scaled(&map, 0, FilterType::Nearest).is_err());
+        // 320 * 205 超过 MAX_DIMENSION
+        assert!(render_scaled(&map, 205, FilterType::Nearest).is_err());
+        assert!(render_scaled(&map, u32::MAX, FilterType::Nearest).is_err());
+    }
+
+    #[test]
+    fn stitch_maps_side_by_side() {
+        let left = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let right = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let world = stitch_maps(&[(left, 0, 0), (right, fixture::WIDTH, 10)]).unwrap();
+        assert_eq!(world.dimensions(), (fixture::WIDTH * 2, fixture::HEIGHT + 10));
+        assert_eq!(world.get_pixel(fixture::WIDTH + 5, 5)[3], 0);
+        assert_eq!(world.get_pixel(fixture::WIDTH + 5, 15)[3], 255);
+
+        let far = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert!(stitch_maps(&[(far, u32::MAX, 0)]).is_err());
+    }
+
+    #[test]
+    fn render_mask_layer_clips_out_of_bounds_masks() {
+        let mut map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert!(map.warnings.is_empty());
+        let mut outside = map.masks[0].clone();
+        outside.x = fixture::WIDTH + 100;
+        outside.index = 1;
+        map.masks[0].x = fixture::WIDTH - 2;
+        map.masks.push(outside);
+
+        let layer = render_mask_layer(&map).unwrap();
+        assert_eq!(layer.dimensions(), (fixture::WIDTH, fixture::HEIGHT));
+        let (_, y, _, _) = fixture::MASK;
+        assert_eq!(layer.get_pixel(fixture::WIDTH - 1, y)[3], 0xF0);
+        let report = map.validate(0);
+        let out_of_bounds = report.warnings.iter().filter(|w| w.contains("exceeds the map"));
+        assert_eq!(out_of_bounds.count(), 2);
+    }
+
+    #[test]
+    fn stream_render_png_matches_render() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let mut png = vec![];
+        stream_render_png(&map, &mut png).unwrap();
+        let streamed = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(streamed, render(&map).unwrap());
+    }
+
+    #[test]
+    fn stream_render_png_matches_render_over_rows() {
+        // 三行单元，最后一行只有 20 像素高，中间有空位
+        let mut builder = crate::builder::MapBuilder::with_dimensions(400, 500);
+        builder.set_tile(0, 0, fixture::tile_jpeg(320, 240));
+        builder.set_tile(1, 1, fixture::tile_jpeg(80, 240));
+        builder.set_tile(2, 0, fixture::tile_jpeg(320, 20));
+        builder.set_tile(2, 1, fixture::tile_png(80, 20));
+        let map = builder.build().unwrap();
+
+        let mut png = vec![];
+        stream_render_png(&map, &mut png).unwrap();
+        let streamed = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(streamed.dimensions(), (400, 500));
+        assert_eq!(streamed, render(&map).unwrap());
+    }
+
+    #[test]
+    fn stream_render_png_of_empty_map() {
+        let mut map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        map.map_header.width = 0;
+        map.units.clear();
+        assert_eq!(render(&map).unwrap().dimensions(), (0, fixture::HEIGHT));
+
+        let mut png = vec![];
+        stream_render_png(&map, &mut png).unwrap();
+        assert!(png.is_empty());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn render_parallel_matches_render() {
+        // 三行单元，右边一列和最后一行不满一个单元，中间有空位
+        let mut builder = crate::builder::MapBuilder::with_dimensions(400, 500);
+        builder.set_tile(0, 0, fixture::tile_jpeg(320, 240));
+        builder.set_tile(1, 1, fixture::tile_jpeg(80, 240));
+        builder.set_tile(2, 0, fixture::tile_jpeg(320, 20));
+        let map = builder.build().unwrap();
+        assert_eq!(render_parallel(&map).unwrap(), render(&map).unwrap());
+    }
+
+    #[test]
+    fn render_with_custom_decoder() {
+        struct Solid;
+        impl TileDecoder for Solid {
+            fn decode(&self, _bytes: &[u8]) -> anyhow::Result<RgbaImage> {
+                Ok(RgbaImage::from_pixel(TILE_WIDTH, TILE_HEIGHT, Rgba([1, 2, 3, 255])))
+            }
+        }
+        let solid = |image: &RgbaImage| image.pixels().all(|pixel| *pixel == Rgba([1, 2, 3, 255]));
+
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let rendered = render_with_decoder(&map, &RenderOptions::default(), &Solid).unwrap();
+        assert!(solid(&rendered.image));
+
+        let mut png = vec![];
+        stream_render_png_with_decoder(&map, &mut png, &Solid).unwrap();
+        assert!(solid(&image::load_from_memory(&png).unwrap().to_rgba8()));
+
+        let scaled = render_scaled_with_decoder(&map, 2, FilterType::Nearest, &Solid).unwrap();
+        assert!(solid(&scaled));
+
+        #[cfg(feature = "rayon")]
+        assert!(solid(&render_parallel_with_decoder(&map, &Solid).unwrap()));
+    }
+
+    #[test]
+    fn try_from_map() {
+        let map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        let image: RgbaImage = (&map).try_into().unwrap();
+        assert_eq!(image.dimensions(), (fixture::WIDTH, fixture::HEIGHT));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::mask::check_mask_size;
+use crate::new_map::{grid_size, Map, MapVersion, KNOWN_UNIT_FLAGS};
+
+/// 地图宽高的上限，超过这个值基本可以认为是文件损坏
+pub const MAX_DIMENSION: u32 = 1 << 16;
+
+/// 地图一致性检查的结果
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    /// 不影响使用，但是值得注意的问题
+    pub warnings: Vec<String>,
+    /// 会导致渲染或导出出错的问题
+    pub errors: Vec<String>,
+}
+
+impl ValidationReport {
+    /// 没有错误 (可以有警告)
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl Map {
+    /// 运行所有一致性检查，file_len 是地图文件的总字节数
+    pub fn validate(&self, file_len: u64) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        let header = &self.map_header;
+
+        if MapVersion::from_magic(&header.magic) != Some(header.version) {
+            report
+                .errors
+                .push(format!("Map magic {:?} does not match {:?}", header.magic, header.version));
+        }
+
+        if header.width == 0 || header.height == 0 {
+            report.errors.push(format!("Empty map {}x{}", header.width, header.height));
+        }
+        if header.width > MAX_DIMENSION || header.height > MAX_DIMENSION {
+            report.errors.push(format!(
+                "Map dimensions {}x{} exceed {}",
+                header.width, header.height, MAX_DIMENSION
+            ));
+        }
+
+        // 解码时索引表可能已经被截短，index_size 也跟着改了，所以按宽高重新算
+        let (rows, cols) = grid_size(header.width, header.height);
+        let declared = rows as u64 * cols as u64;
+        if header.map_index_list.len() as u64 != declared {
+            report.errors.push(format!(
+                "Index table has {} entries, {}x{} tiles are expected",
+                header.map_index_list.len(),
+                rows,
+                cols
+            ));
+        }
+        for (slot, offset) in header.map_index_list.iter().enumerate() {
+            if *offset as u64 >= file_len {
+                report.errors.push(format!(
+                    "Unit {} offset {} is beyond the end of file ({})",
+                    slot, offset, file_len
+                ));
+            }
+        }
+
+        // 不认识的单元类型在解码时被跳过了
+        if self.units.len() != header.index_size as usize {
+            report.warnings.push(format!(
+                "Decoded {} units, header declares {} (units with unknown flags are skipped)",
+                self.units.len(),
+                header.index_size
+            ));
+        }
+
+        for unit in self.units.iter() {
+            let flag = unit.unit_flag.as_bytes();
+            if !KNOWN_UNIT_FLAGS.iter().any(|known| known.as_slice() == flag) {
+                report
+                    .warnings
+                    .push(format!("Unit {} has unknown flag {:?}", unit.slot, unit.unit_flag));
+            }
+        }
+
+        for mask in self.masks.iter() {
+            if let Err(e) = check_mask_size(mask, 0) {
+                report.errors.push(format!("Mask {}: {}", mask.index(), e));
+            }
+            // 渲染时会被裁剪，不会出错
+            if !mask.fits_in(header.width, header.height) {
+                report.warnings.push(format!(
+                    "Mask {} at ({}, {}) with size {}x{} exceeds the map",
+                    mask.index(),
+                    mask.x(),
+                    mask.y(),
+                    mask.width(),
+                    mask.height()
+                ));
+            }
+        }
+
+        for gap in self.layout.gaps(file_len) {
+            report.warnings.push(format!("Unparsed bytes {}..{}", gap.start, gap.end));
+        }
+
+        report
+    }
+
+    /// 逐个解码单元，返回解码失败的单元位置和错误信息，不分配整张地图的图片，
+    /// 用来在构建时检查所有单元都能正常显示
+    pub fn validate_render(&self) -> Vec<(usize, String)> {
+        self.units
+            .iter()
+            .filter_map(|unit| match unit.decode() {
+                Ok(_) => None,
+                Err(e) => Some((unit.slot, format!("{:#}", e))),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::new_map::{decode_from_bytes, Map};
+
+    /// 解码测试地图，返回地图和文件长度
+    fn sample() -> (Map, u64) {
+        let bytes = fixture::map_bytes();
+        (decode_from_bytes(&bytes).unwrap(), bytes.len() as u64)
+    }
+
+    #[test]
+    fn validate_fixture_is_clean() {
+        let (map, file_len) = sample();
+        let report = map.validate(file_len);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    }
+
+    #[test]
+    fn validate_reports_bad_dimensions() {
+        let (mut map, file_len) = sample();
+        map.map_header.width = 0;
+        assert!(map.validate(file_len).errors.iter().any(|e| e.starts_with("Empty map")));
+
+        map.map_header.width = super::MAX_DIMENSION + 1;
+        assert!(map.validate(file_len).errors.iter().any(|e| e.contains("exceed")));
+    }
+
+    #[test]
+    fn validate_reports_index_problems() {
+        let (mut map, file_len) = sample();
+        map.map_header.map_index_list[0] = file_len as u32;
+        let errors = map.validate(file_len).errors;
+        assert!(errors.iter().any(|e| e.contains("beyond the end of file")));
+
+        // 解码时截短索引表会同时改 index_size，这里只改列表也要能发现
+        let (mut map, _) = sample();
+        map.map_header.map_index_list.clear();
+        map.map_header.index_size = 0;
+        let errors = map.validate(file_len).errors;
+        assert!(errors.iter().any(|e| e.starts_with("Index table has 0 entries")));
+    }
+
+    #[test]
+    fn validate_reports_unit_problems() {
+        let (mut map, file_len) = sample();
+        map.units[0].unit_flag = "ABCD".to_string();
+        let warnings = map.validate(file_len).warnings;
+        assert!(warnings.iter().any(|w| w.contains("unknown flag \"ABCD\"")));
+
+        map.units.clear();
+        let warnings = map.validate(file_len).warnings;
+        assert!(warnings.iter().any(|w| w.starts_with("Decoded 0 units")));
+    }
+
+    #[test]
+    fn validate_reports_mask_size_mismatch() {
+        let (mut map, file_len) = sample();
+        map.masks[0].data.push(0);
+        let report = map.validate(file_len);
+        assert!(report.errors.iter().any(|e| e.starts_with("Mask 0:")));
+    }
+
+    #[test]
+    fn validate_reports_uncovered_gaps() {
+        let (map, file_len) = sample();
+        let warnings = map.validate(file_len + 16).warnings;
+        assert_eq!(warnings, vec![format!("Unparsed bytes {}..{}", file_len, file_len + 16)]);
+    }
+
+    #[test]
+    fn validate_render_reports_bad_tiles() {
+        let mut map = decode_from_bytes(&fixture::map_bytes()).unwrap();
+        assert!(map.validate_render().is_empty());
+
+        map.units[0].unit_data.truncate(20);
+        let failures = map.validate_render();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, 0);
+    }
+}